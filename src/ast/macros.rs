@@ -10,14 +10,6 @@ macro_rules! takes {
     }};
 }
 
-macro_rules! has {
-    ($pairs:expr, $typed:literal) => {
-        $pairs
-            .into_inner()
-            .any(|a| format!("{:?}", a.as_rule()) == $typed.to_string())
-    };
-}
-
 macro_rules! ident {
     ($pair:expr, $h:expr) => {
         match format!("{:?}", $pair.as_rule()) == "identifier".to_string() {
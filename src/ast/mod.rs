@@ -6,6 +6,7 @@ use parser::build_ast_from_expr;
 use pest::{Parser, Span};
 
 use crate::{
+    checker::check,
     errors::{Error, Hydrator},
     eval::eval,
     object::ContextualObject,
@@ -19,9 +20,9 @@ pub mod op;
 mod parser;
 
 #[derive(Clone, Debug, PartialEq)]
-pub struct ContextualNode<'a>(pub Node<'a>, pub Span<'a>);
-impl Eq for ContextualNode<'_> {}
-impl PartialOrd for ContextualNode<'_> {
+pub struct ContextualNode(pub Node, pub Span<'static>);
+impl Eq for ContextualNode {}
+impl PartialOrd for ContextualNode {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match self.0.partial_cmp(&other.0) {
             Some(core::cmp::Ordering::Equal) => {
@@ -32,14 +33,14 @@ impl PartialOrd for ContextualNode<'_> {
     }
 }
 
-impl Ord for ContextualNode<'_> {
+impl Ord for ContextualNode {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.partial_cmp(other).unwrap()
     }
 }
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
-pub enum Node<'a> {
+pub enum Node {
     // Literals
     Float(f64),
     Int(i128),
@@ -50,91 +51,220 @@ pub enum Node<'a> {
     // Operators
     MondaicOp {
         verb: Mondaic,
-        expr: Box<ContextualNode<'a>>,
+        expr: Box<ContextualNode>,
     },
 
     DyadicOp {
         verb: Dyadic,
-        lhs: Box<ContextualNode<'a>>,
-        rhs: Box<ContextualNode<'a>>,
+        lhs: Box<ContextualNode>,
+        rhs: Box<ContextualNode>,
     },
 
-    Terms(Vec<ContextualNode<'a>>),
+    Interpolated(Vec<ContextualNode>),
+    Map(Vec<(ContextualNode, ContextualNode)>),
+    Terms(Vec<ContextualNode>),
+    // A doc comment with nothing to document
+    Doc(String),
     Ident(String),
-    Index(Box<ContextualNode<'a>>, Vec<ContextualNode<'a>>),
-    Return(Box<ContextualNode<'a>>),
+    Index(Box<ContextualNode>, Vec<ContextualNode>),
+    Return(Box<ContextualNode>),
 
     Delclaration {
+        doc: Option<String>,
+        public: bool,
         ident: String,
         typed: Option<String>,
-        expr: Box<ContextualNode<'a>>,
+        expr: Box<ContextualNode>,
     },
 
     Assignment {
         ident: String,
-        expr: Box<ContextualNode<'a>>,
+        path: Vec<ContextualNode>,
+        // The verb of a compound assignment into `path`, like `a[i] += 1`
+        compound: Option<Dyadic>,
+        expr: Box<ContextualNode>,
     },
 
     Conditional {
-        arms: Vec<(ContextualNode<'a>, Vec<ContextualNode<'a>>)>,
-        else_arm: Option<Vec<ContextualNode<'a>>>,
+        arms: Vec<(ContextualNode, Vec<ContextualNode>)>,
+        else_arm: Option<Vec<ContextualNode>>,
     },
 
     LoopWhile {
-        condition: Box<ContextualNode<'a>>,
-        body: Vec<ContextualNode<'a>>,
+        label: Option<String>,
+        condition: Box<ContextualNode>,
+        body: Vec<ContextualNode>,
+    },
+
+    LoopFor {
+        label: Option<String>,
+        pattern: ContextualPattern,
+        iterable: Box<ContextualNode>,
+        body: Vec<ContextualNode>,
+    },
+
+    Loop {
+        label: Option<String>,
+        body: Vec<ContextualNode>,
+    },
+
+    Break {
+        label: Option<String>,
+        expr: Option<Box<ContextualNode>>,
+    },
+
+    Continue {
+        label: Option<String>,
+    },
+
+    Throw(Box<ContextualNode>),
+    Yield(Option<Box<ContextualNode>>),
+    // The body of a function that yields, run lazily when it's called
+    Generator(Vec<ContextualNode>),
+
+    // Queued to run as a task, promising a value of type `typed`
+    Async {
+        typed: String,
+        body: Vec<ContextualNode>,
+    },
+    Await(Box<ContextualNode>),
+
+    Try {
+        body: Vec<ContextualNode>,
+        catch: Option<(Option<String>, Vec<ContextualNode>)>,
+        finally: Option<Vec<ContextualNode>>,
+    },
+
+    Range {
+        start: Box<ContextualNode>,
+        end: Box<ContextualNode>,
+        inclusive: bool,
     },
 
     FunctionDeclaration {
+        doc: Option<String>,
+        public: bool,
         ident: String,
         args: Vec<(String, String)>,
         return_type: Option<String>,
-        body: Vec<ContextualNode<'a>>,
+        body: Vec<ContextualNode>,
     },
 
     FunctionCall {
         ident: String,
-        args: Vec<ContextualNode<'a>>,
+        args: Vec<ContextualNode>,
     },
 
     Lambda {
-        args: Vec<String>,
+        args: Vec<(String, String)>,
         return_type: Option<String>,
-        body: Vec<ContextualNode<'a>>,
+        body: Vec<ContextualNode>,
+    },
+
+    StructDeclaration {
+        doc: Option<String>,
+        public: bool,
+        ident: String,
+        fields: Vec<(String, String)>,
     },
 
     Struct {
         ident: String,
+        fields: Vec<(ContextualNode, ContextualNode)>,
+    },
+
+    Import {
+        path: String,
+        alias: Option<String>,
+        names: Vec<ContextualNode>,
+    },
+
+    Impl {
         typed: String,
-        fields: Vec<(String, ContextualNode<'a>)>,
+        trait_name: Option<String>,
+        methods: Vec<ContextualNode>,
     },
 
-    Array(Vec<ContextualNode<'a>>),
+    EnumDeclaration {
+        public: bool,
+        ident: String,
+        variants: Vec<(String, VariantShape)>,
+    },
+
+    Match {
+        expr: Box<ContextualNode>,
+        arms: Vec<(
+            ContextualPattern,
+            Option<ContextualNode>,
+            Vec<ContextualNode>,
+        )>,
+    },
+
+    TraitDeclaration {
+        public: bool,
+        ident: String,
+        required: Vec<String>,
+        defaults: Vec<ContextualNode>,
+    },
+
+    Array(Vec<ContextualNode>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VariantShape {
+    Unit,
+    Tuple(Vec<String>),
+    Struct(Vec<(String, String)>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContextualPattern(pub Pattern, pub Span<'static>);
+impl PartialOrd for ContextualPattern {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        match self.0.partial_cmp(&other.0) {
+            Some(core::cmp::Ordering::Equal) => {
+                self.1.start_pos().partial_cmp(&other.1.start_pos())
+            }
+            ord => ord,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum Pattern {
+    Wildcard,
+    Literal(Box<ContextualNode>),
+    Binding(String),
+    Array(Vec<ContextualPattern>),
+    Struct(String, Vec<(String, ContextualPattern)>),
+    Variant(String, String, Option<Box<ContextualPattern>>),
 }
 
-impl<'a> Node<'a> {
-    pub fn provide_context(self, span: Span<'a>) -> ContextualNode<'a> {
+impl Node {
+    pub fn provide_context(self, span: Span<'static>) -> ContextualNode {
         ContextualNode(self, span)
     }
 }
 
-impl ContextualNode<'_> {
+impl ContextualNode {
     pub fn inner(&self) -> &Node {
         &self.0
     }
 
-    pub fn span(&self) -> Span<'_> {
+    pub fn span(&self) -> Span<'static> {
         self.1.clone()
     }
 }
 
-pub struct Program<'a> {
-    pub tree: Vec<ContextualNode<'a>>,
+pub struct Program {
+    pub tree: Vec<ContextualNode>,
     hydrator: Hydrator,
 }
 
-impl<'a> Program<'a> {
-    pub fn make(input: String, path: Option<String>) -> miette::Result<Program<'a>> {
+impl Program {
+    /// Parses `input`. The source is leaked, so that the spans pointing into
+    /// it can go anywhere values do, including other threads.
+    pub fn make(input: String, path: Option<String>) -> miette::Result<Program> {
         let refed = Box::leak(input.clone().into_boxed_str());
 
         let h: Hydrator = (
@@ -143,20 +273,20 @@ impl<'a> Program<'a> {
         );
 
         let pairs = PetParser::parse(Rule::program, refed).map_err(|e| {
-            let span = match e.line_col {
-                pest::error::LineColLocation::Pos((l, c)) => (l, c),
-                pest::error::LineColLocation::Span((l, c), _) => (l, c),
+            let span = match e.location {
+                pest::error::InputLocation::Pos(p) => (p, p),
+                pest::error::InputLocation::Span((s, e)) => (s, e),
             };
 
             partial!(
                 "parsing input",
-                e.to_string(),
-                Span::new(&h.1.to_string(), span.0, span.1).unwrap(),
+                e.variant.message(),
+                Span::new(refed, span.0, span.1).unwrap(),
                 h
             )
         })?;
 
-        let mut ast: Vec<ContextualNode<'a>> = vec![];
+        let mut ast: Vec<ContextualNode> = vec![];
         for pair in pairs {
             match pair.as_rule() {
                 Rule::expr | Rule::ltl => {
@@ -172,14 +302,81 @@ impl<'a> Program<'a> {
         })
     }
 
-    pub fn eval(self, scope: Option<MutScope<'a>>) -> miette::Result<ContextualObject<'a>> {
+    /// Type checks the program without running it.
+    pub fn check(&self) -> Vec<Error> {
+        check(&self.tree, self.hydrator.clone())
+    }
+
+    /// The doc comments of the program's items, along with the signature of
+    /// the item each one documents, in the order they're declared.
+    pub fn docs(&self) -> Vec<(String, String)> {
+        let mut docs = Vec::new();
+        for node in self.tree.iter() {
+            match &node.0 {
+                Node::Impl { typed, methods, .. } => {
+                    for method in methods {
+                        docs.extend(documented(method, Some(typed)));
+                    }
+                }
+                _ => docs.extend(documented(node, None)),
+            }
+        }
+        docs
+    }
+
+    pub fn eval(self, scope: Option<MutScope>) -> miette::Result<ContextualObject> {
         let h = self.hydrator.clone();
         eval(self, scope.unwrap_or(Scope::new("#pet.repl")), h)
     }
 }
 
-impl<'a> From<(Vec<ContextualNode<'a>>, Hydrator)> for Program<'a> {
-    fn from(tree: (Vec<ContextualNode<'a>>, Hydrator)) -> Self {
+// The signature and doc comment of a documented item, with methods named
+// after the type they belong to
+fn documented(node: &ContextualNode, owner: Option<&str>) -> Option<(String, String)> {
+    let listed = |pairs: &[(String, String)]| {
+        pairs
+            .iter()
+            .map(|(name, typed)| match name.as_str() {
+                "self" => name.clone(),
+                _ => format!("{name}: {typed}"),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    match &node.0 {
+        Node::FunctionDeclaration {
+            doc: Some(doc),
+            ident,
+            args,
+            return_type,
+            ..
+        } => {
+            let owner = owner.map(|o| format!("{o}.")).unwrap_or_default();
+            let ret = return_type.as_ref().map(|r| format!(": {r}")).unwrap_or_default();
+            Some((format!("fn {owner}{ident}({}){ret}", listed(args)), doc.clone()))
+        }
+        Node::StructDeclaration {
+            doc: Some(doc),
+            ident,
+            fields,
+            ..
+        } => Some((format!("struct {ident} {{ {} }}", listed(fields)), doc.clone())),
+        Node::Delclaration {
+            doc: Some(doc),
+            ident,
+            typed,
+            ..
+        } => {
+            let typed = typed.as_ref().map(|t| format!(": {t}")).unwrap_or_default();
+            Some((format!("let {ident}{typed}"), doc.clone()))
+        }
+        _ => None,
+    }
+}
+
+impl From<(Vec<ContextualNode>, Hydrator)> for Program {
+    fn from(tree: (Vec<ContextualNode>, Hydrator)) -> Self {
         Program {
             tree: tree.0,
             hydrator: tree.1,
@@ -1,9 +1,15 @@
 use std::collections::HashMap;
 
+use pest::pratt_parser::{Assoc, Op, PrattParser};
+
+use crate::Rule;
+
 thread_local! {
     pub static MONDAIC_SYMBOL_MAP: HashMap<&'static str, Mondaic> = {
         let mut m = HashMap::new();
         m.insert("!", Mondaic::Negate);
+        m.insert("-", Mondaic::Negative);
+        m.insert("~", Mondaic::BitNot);
         m
     };
 
@@ -11,12 +17,21 @@ thread_local! {
         let mut m = HashMap::new();
         m.insert("**", Dyadic::Pow);
         m.insert("==", Dyadic::Equality);
+        m.insert("!=", Dyadic::NotEqual);
         m.insert("+", Dyadic::Add);
         m.insert("-", Dyadic::Subtract);
         m.insert("*", Dyadic::Multiply);
         m.insert("/", Dyadic::Divide);
+        m.insert("~/", Dyadic::IntDivide);
+        m.insert("%", Dyadic::Modulo);
+        m.insert("&", Dyadic::BitAnd);
+        m.insert("|", Dyadic::BitOr);
+        m.insert("^", Dyadic::BitXor);
+        m.insert("<<", Dyadic::ShiftLeft);
+        m.insert(">>", Dyadic::ShiftRight);
         m.insert("||", Dyadic::Or);
         m.insert("&&", Dyadic::And);
+        m.insert("??", Dyadic::Coalesce);
         m.insert(">", Dyadic::GreaterThan);
         m.insert("<", Dyadic::LessThan);
         m.insert(">=", Dyadic::GreaterThanOrEqual);
@@ -25,6 +40,50 @@ thread_local! {
     };
 }
 
+thread_local! {
+    /// Binding powers for dyads, loosest first. Everything on one line binds
+    /// equally tight, and associates to the left unless noted.
+    ///
+    /// | verbs                 |                   |
+    /// |-----------------------|-------------------|
+    /// | `..` `..=`            |                   |
+    /// | `??`                  |                   |
+    /// | `\|\|`                |                   |
+    /// | `&&`                  |                   |
+    /// | `==` `!=`             |                   |
+    /// | `<` `>` `<=` `>=`     |                   |
+    /// | `\|`                  |                   |
+    /// | `^`                   |                   |
+    /// | `&`                   |                   |
+    /// | `<<` `>>`             |                   |
+    /// | `+` `-`               |                   |
+    /// | `*` `/` `~/` `%`      |                   |
+    /// | `**`                  | right associative |
+    ///
+    /// Monads aren't part of this table, they always bind tighter than any dyad.
+    pub static PRATT_PARSER: PrattParser<Rule> = PrattParser::new()
+        .op(Op::infix(Rule::range_exclusive, Assoc::Left)
+            | Op::infix(Rule::range_inclusive, Assoc::Left))
+        .op(Op::infix(Rule::coalesce, Assoc::Left))
+        .op(Op::infix(Rule::or, Assoc::Left))
+        .op(Op::infix(Rule::and, Assoc::Left))
+        .op(Op::infix(Rule::equality, Assoc::Left) | Op::infix(Rule::not_equal, Assoc::Left))
+        .op(Op::infix(Rule::lt, Assoc::Left)
+            | Op::infix(Rule::gt, Assoc::Left)
+            | Op::infix(Rule::lte, Assoc::Left)
+            | Op::infix(Rule::gte, Assoc::Left))
+        .op(Op::infix(Rule::bit_or, Assoc::Left))
+        .op(Op::infix(Rule::bit_xor, Assoc::Left))
+        .op(Op::infix(Rule::bit_and, Assoc::Left))
+        .op(Op::infix(Rule::shl, Assoc::Left) | Op::infix(Rule::shr, Assoc::Left))
+        .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::subtract, Assoc::Left))
+        .op(Op::infix(Rule::multiply, Assoc::Left)
+            | Op::infix(Rule::divide, Assoc::Left)
+            | Op::infix(Rule::int_divide, Assoc::Left)
+            | Op::infix(Rule::modulo, Assoc::Left))
+        .op(Op::infix(Rule::pow, Assoc::Right));
+}
+
 pub fn get_dyadic(verb: String) -> Option<Dyadic> {
     DYADIC_SYMBOL_MAP.with(|m| m.get(verb.as_str()).copied())
 }
@@ -44,6 +103,8 @@ pub fn get_monads() -> Vec<String> {
 #[derive(Debug, Hash, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Mondaic {
     Negate,
+    Negative,
+    BitNot,
 }
 
 impl Mondaic {
@@ -56,12 +117,21 @@ impl Mondaic {
 pub enum Dyadic {
     Pow,
     Equality,
+    NotEqual,
     Add,
     Subtract,
     Multiply,
     Divide,
+    IntDivide,
+    Modulo,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
     And,
     Or,
+    Coalesce,
     GreaterThan,
     LessThan,
     GreaterThanOrEqual,
@@ -1,18 +1,24 @@
+use std::iter::Peekable;
+
 use itertools::Itertools;
-use pest::iterators::Pair;
+use pest::{
+    iterators::{Pair, Pairs},
+    Span,
+};
 
 use crate::{
     errors::{Error, Hydrator},
+    helpers::extend,
     Rule,
 };
 
 use super::{
-    op::{get_dyadic, get_dyads, get_monads, get_mondaic},
-    ContextualNode, Node,
+    op::{get_dyadic, get_dyads, get_monads, get_mondaic, PRATT_PARSER},
+    ContextualNode, ContextualPattern, Node, Pattern, VariantShape,
 };
-type NodeRes<'a> = Result<ContextualNode<'a>, Error>;
+type NodeRes = Result<ContextualNode, Error>;
 
-pub fn build_ast_from_expr<'a>(e: Pair<'a, Rule>, h: Hydrator) -> NodeRes<'a> {
+pub fn build_ast_from_expr(e: Pair<'static, Rule>, h: Hydrator) -> NodeRes {
     match e.as_rule() {
         Rule::expr | Rule::ltl => {
             build_ast_from_expr(e.clone().into_inner().next().unwrap(), h.clone())
@@ -35,50 +41,76 @@ pub fn build_ast_from_expr<'a>(e: Pair<'a, Rule>, h: Hydrator) -> NodeRes<'a> {
         | Rule::int
         | Rule::null
         | Rule::identifier
-        | Rule::array => build_ast_from_term(e.clone(), h),
+        | Rule::array
+        | Rule::map => build_ast_from_term(e.clone(), h),
 
         Rule::monadic => {
             let (verb, expr) = takes!(e, 2);
-            build_mondaic(verb, build!(expr, h), h)
+            build_mondaic(verb, build!(expr, h), e.as_span(), h)
         }
-        Rule::dyadic => {
-            let mut inner = e.clone().into_inner().rev();
-            let mut right = build!(inner.next().unwrap(), h);
+        Rule::dyadic => PRATT_PARSER.with(|pratt| {
+            pratt
+                .map_primary(|operand| build_ast_from_expr(operand, h.clone()))
+                .map_infix(|lhs, verb, rhs| build_dyadic(verb, lhs?, rhs?, h.clone()))
+                .parse(e.clone().into_inner())
+        }),
 
-            for chunk in &inner.chunks(2) {
-                let (verb, left) = chunk.collect_tuple().unwrap();
-                let left = build!(left, h.clone());
-                right = build_dyadic(verb, left, right, h.clone())?;
-            }
+        Rule::var_decl => {
+            let mut inner = e.clone().into_inner().peekable();
+            let doc = take_doc(&mut inner);
+            let public = take_public(&mut inner);
+            let mut inner = inner.skip_while(|p| p.as_rule() == Rule::declaration);
+
+            let ident = inner.next().unwrap();
+            let (typed, expr) = match inner.next().unwrap() {
+                t if t.as_rule() == Rule::typed => (Some(typed_name(t)), inner.next().unwrap()),
+                expr => (None, expr),
+            };
 
-            Ok(right)
+            Ok(Node::Delclaration {
+                doc,
+                public,
+                ident: ident!(ident, h.clone())?,
+                typed,
+                expr: Box::new(build_ast_from_expr(expr, h.clone())?),
+            }
+            .provide_context(e.as_span()))
         }
 
-        Rule::var_decl => {
-            if has!(e.clone(), "colon") {
-                let (_, ident, _, typed, expr) = takes!(e.clone(), 5);
-                Ok(Node::Delclaration {
-                    ident: ident!(ident, h.clone())?,
-                    typed: Some(typed.as_str().to_string()),
-                    expr: Box::new(build_ast_from_expr(expr, h.clone())?),
-                }
-                .provide_context(e.as_span()))
-            } else {
-                let (_, ident, expr) = takes!(e.clone(), 3);
-                Ok(Node::Delclaration {
-                    ident: ident!(ident, h.clone())?,
-                    typed: None,
-                    expr: Box::new(build_ast_from_expr(expr, h.clone())?),
+        Rule::doc => Ok(Node::Doc(doc_text(e.clone())).provide_context(e.as_span())),
+
+        Rule::var_assign => {
+            let mut inner = e.clone().into_inner();
+            let place = inner.next().unwrap();
+            let mut steps = place.clone().into_inner();
+            let ident = steps.next().unwrap();
+            let path: Vec<_> = steps.map(|i| build_postfix(i, h.clone())).try_collect()?;
+            let (compound, expr) = match inner.next().unwrap() {
+                c if c.as_rule() == Rule::compound => (Some(c), inner.next().unwrap()),
+                expr => (None, expr),
+            };
+            let mut expr = build_ast_from_expr(expr, h.clone())?;
+            let mut compound =
+                compound.map(|c| get_dyadic(c.as_str().trim_end_matches('=').to_string()).unwrap());
+
+            // `x += 1` is sugar for `x = x + 1`. With a path, the target is
+            // only evaluated once, so that's left for the assignment to do.
+            if let Some(verb) = compound.filter(|_| path.is_empty()) {
+                let target = Node::Ident(ident.as_str().to_string()).provide_context(ident.as_span());
+                expr = Node::DyadicOp {
+                    verb,
+                    lhs: Box::new(target),
+                    rhs: Box::new(expr),
                 }
-                .provide_context(e.as_span()))
+                .provide_context(e.as_span());
+                compound = None;
             }
-        }
 
-        Rule::var_assign => {
-            let (ident, expr) = takes!(e.clone(), 2);
             Ok(Node::Assignment {
                 ident: ident!(ident, h.clone())?,
-                expr: Box::new(build_ast_from_expr(expr, h.clone())?),
+                path,
+                compound,
+                expr: Box::new(expr),
             }
             .provide_context(e.as_span()))
         }
@@ -119,38 +151,121 @@ pub fn build_ast_from_expr<'a>(e: Pair<'a, Rule>, h: Hydrator) -> NodeRes<'a> {
         }
 
         Rule::loop_while => {
-            let condition = build!(e.clone().into_inner().next().unwrap(), h.clone());
-            let body = e
-                .clone()
-                .into_inner()
-                .last()
-                .unwrap()
-                .into_inner()
-                .map(|t| build_ast_from_expr(t, h.clone()))
-                .try_collect()?;
+            let mut inner = e.clone().into_inner().peekable();
+            let label = take_label(&mut inner);
+            let (condition, block) = inner.collect_tuple().unwrap();
 
             Ok(Node::LoopWhile {
-                condition: Box::new(condition),
+                label,
+                condition: Box::new(build!(condition, h.clone())),
+                body: build_block(block, h)?,
+            }
+            .provide_context(e.as_span()))
+        }
+
+        Rule::loop_for => {
+            let mut inner = e.clone().into_inner().peekable();
+            let label = take_label(&mut inner);
+            let (pattern, iterable, block) = inner.collect_tuple().unwrap();
+
+            Ok(Node::LoopFor {
+                label,
+                pattern: build_pattern(pattern, h.clone())?,
+                iterable: Box::new(build!(iterable, h)),
+                body: build_block(block, h)?,
+            }
+            .provide_context(e.as_span()))
+        }
+
+        Rule::loop_forever => {
+            let mut inner = e.clone().into_inner().peekable();
+            let label = take_label(&mut inner);
+
+            Ok(Node::Loop {
+                label,
+                body: build_block(inner.next().unwrap(), h)?,
+            }
+            .provide_context(e.as_span()))
+        }
+
+        Rule::break_stmt => {
+            let mut inner = e.clone().into_inner().peekable();
+            let label = take_label(&mut inner);
+            let expr = match inner.next() {
+                Some(expr) => Some(Box::new(build!(expr, h))),
+                None => None,
+            };
+
+            Ok(Node::Break { label, expr }.provide_context(e.as_span()))
+        }
+
+        Rule::continue_stmt => {
+            let mut inner = e.clone().into_inner().peekable();
+            Ok(Node::Continue {
+                label: take_label(&mut inner),
+            }
+            .provide_context(e.as_span()))
+        }
+
+        Rule::yield_stmt => {
+            let expr = match e.clone().into_inner().next() {
+                Some(expr) => Some(Box::new(build!(expr, h))),
+                None => None,
+            };
+            Ok(Node::Yield(expr).provide_context(e.as_span()))
+        }
+
+        Rule::async_block => {
+            let block = e.clone().into_inner().last().unwrap();
+            Ok(Node::Async {
+                typed: "any".to_string(),
+                body: build_block(block, h)?,
+            }
+            .provide_context(e.as_span()))
+        }
+
+        Rule::await_expr => {
+            let expr = e.clone().into_inner().next().unwrap();
+            Ok(Node::Await(Box::new(build!(expr, h))).provide_context(e.as_span()))
+        }
+
+        Rule::throw_stmt => {
+            let expr = e.clone().into_inner().next().unwrap();
+            Ok(Node::Throw(Box::new(build!(expr, h))).provide_context(e.as_span()))
+        }
+
+        Rule::try_stmt => {
+            let mut inner = e.clone().into_inner();
+            let body = build_block(inner.next().unwrap(), h.clone())?;
+            let mut catch = None;
+            let mut finally = None;
+
+            for arm in inner {
+                let mut parts = arm.clone().into_inner().collect::<Vec<_>>();
+                let block = build_block(parts.pop().unwrap(), h.clone())?;
+
+                match arm.as_rule() {
+                    Rule::catch_arm => {
+                        let binding = parts.first().map(|p| p.as_str().to_string());
+                        catch = Some((binding, block));
+                    }
+                    _ => finally = Some(block),
+                }
+            }
+
+            Ok(Node::Try {
                 body,
+                catch,
+                finally,
             }
             .provide_context(e.as_span()))
         }
 
         Rule::fn_decl => {
-            let (outline, block) = takes!(e.clone(), 2);
-            let body = block
-                .clone()
-                .into_inner()
-                .map(|t| build_ast_from_expr(t, h.clone()))
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|_| {
-                    partial!(
-                        "building function body",
-                        e.to_string(),
-                        block.as_span(),
-                        h.clone()
-                    )
-                });
+            let mut inner = e.clone().into_inner().peekable();
+            let doc = take_doc(&mut inner);
+            let (outline, block) = inner.collect_tuple().unwrap();
+            let body = build_function_body(block.clone(), h.clone());
 
             let identifier = outline
                 .clone()
@@ -162,29 +277,233 @@ pub fn build_ast_from_expr<'a>(e: Pair<'a, Rule>, h: Hydrator) -> NodeRes<'a> {
                 .into_inner()
                 .last()
                 .filter(|p| p.as_rule() == Rule::typed)
-                .map(|n| n.as_str().to_string());
+                .map(typed_name);
             let args = outline
                 .clone()
                 .into_inner()
                 .find(|p| p.as_rule() == Rule::typed_args)
-                .map(|n| {
-                    n.into_inner()
-                        .map(|n| {
-                            let (a, _, b) = takes!(n, 3);
-                            (a.as_str().to_string(), b.as_str().to_string())
-                        })
-                        .collect::<Vec<_>>()
-                });
+                .map(typed_vars);
+
+            let public = take_public(&mut outline.clone().into_inner().peekable());
+            let asynchronous = outline
+                .clone()
+                .into_inner()
+                .any(|p| p.as_rule() == Rule::async_kw);
+
+            // Async functions hand back a promise of what they'd return
+            let (return_type, body) = match asynchronous {
+                false => (return_type, body?),
+                true => {
+                    let task = Node::Async {
+                        typed: return_type.unwrap_or("any".to_string()),
+                        body: body?,
+                    }
+                    .provide_context(block.as_span());
+                    let body = vec![Node::Return(Box::new(task)).provide_context(block.as_span())];
+                    (Some("promise".to_string()), body)
+                }
+            };
 
             Ok(Node::FunctionDeclaration {
+                doc,
+                public,
                 ident: identifier.as_str().to_string(),
                 args: args.unwrap_or_default(),
                 return_type,
-                body: body?,
+                body,
             }
             .provide_context(e.as_span()))
         }
 
+        Rule::struct_decl => {
+            let mut inner = e.clone().into_inner().peekable();
+            let doc = take_doc(&mut inner);
+            let public = take_public(&mut inner);
+            let (ident, fields) = inner.collect_tuple().unwrap();
+
+            let ident = ident!(ident, h.clone())?;
+            let fields = typed_vars(fields);
+
+            Ok(Node::StructDeclaration {
+                doc,
+                public,
+                ident,
+                fields,
+            }
+            .provide_context(e.as_span()))
+        }
+
+        Rule::import_stmt | Rule::from_import => {
+            let mut inner = e.clone().into_inner();
+            let path = inner.next().unwrap();
+            let path = match build!(path.clone(), h).0 {
+                Node::String(path) => path,
+                _ => {
+                    return Err(partial!(
+                        "parsing import",
+                        "Import paths can't be interpolated",
+                        path.as_span(),
+                        h.clone()
+                    ))
+                }
+            };
+
+            let names = inner
+                .map(|name| Node::Ident(name.as_str().to_string()).provide_context(name.as_span()))
+                .collect::<Vec<_>>();
+
+            // `import "..." as name` binds the whole module to its one name
+            let (alias, names) = match e.as_rule() {
+                Rule::import_stmt => (Some(names[0].1.as_str().to_string()), vec![]),
+                _ => (None, names),
+            };
+
+            Ok(Node::Import { path, alias, names }.provide_context(e.as_span()))
+        }
+
+        Rule::impl_block => {
+            let names = e
+                .clone()
+                .into_inner()
+                .filter(|p| p.as_rule() == Rule::identifier)
+                .map(|p| p.as_str().to_string())
+                .collect::<Vec<_>>();
+
+            // `impl Trait for Type` names the trait first
+            let (typed, trait_name) = match names.as_slice() {
+                [typed] => (typed.clone(), None),
+                [trait_name, typed] => (typed.clone(), Some(trait_name.clone())),
+                _ => unreachable!(),
+            };
+
+            let methods = e
+                .clone()
+                .into_inner()
+                .filter(|p| p.as_rule() == Rule::fn_decl)
+                .map(|m| build_ast_from_expr(m, h.clone()))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(Node::Impl {
+                typed,
+                trait_name,
+                methods,
+            }
+            .provide_context(e.as_span()))
+        }
+
+        Rule::enum_decl => {
+            let mut inner = e.clone().into_inner().peekable();
+            let public = take_public(&mut inner);
+            let ident = inner.next().unwrap();
+            let ident = ident!(ident, h.clone())?;
+
+            let variants = inner
+                .map(|variant| {
+                    let mut parts = variant.into_inner();
+                    let name = parts.next().unwrap().as_str().to_string();
+                    let shape = match parts.next() {
+                        None => VariantShape::Unit,
+                        Some(p) if p.as_rule() == Rule::variant_tuple => VariantShape::Tuple(
+                            p.into_inner().map(|t| t.as_str().to_string()).collect(),
+                        ),
+                        Some(p) => VariantShape::Struct(typed_vars(p)),
+                    };
+
+                    (name, shape)
+                })
+                .collect();
+
+            Ok(Node::EnumDeclaration {
+                public,
+                ident,
+                variants,
+            }
+            .provide_context(e.as_span()))
+        }
+
+        Rule::match_expr => {
+            let mut inner = e.clone().into_inner();
+            let expr = build!(inner.next().unwrap(), h);
+
+            let arms = inner
+                .map(|arm| {
+                    let parts = arm.into_inner().collect::<Vec<_>>();
+                    let pattern = build_pattern(parts.first().unwrap().clone(), h.clone())?;
+                    let guard = match parts.len() {
+                        3 => Some(build!(parts[1].clone(), h)),
+                        _ => None,
+                    };
+
+                    let body = parts.last().unwrap().clone();
+                    let body = match body.as_rule() {
+                        Rule::block => body
+                            .into_inner()
+                            .map(|t| build_ast_from_expr(t, h.clone()))
+                            .collect::<Result<Vec<_>, _>>()?,
+                        _ => vec![build!(body, h)],
+                    };
+
+                    Ok((pattern, guard, body))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+
+            Ok(Node::Match {
+                expr: Box::new(expr),
+                arms,
+            }
+            .provide_context(e.as_span()))
+        }
+
+        Rule::trait_decl => {
+            let mut inner = e.clone().into_inner().peekable();
+            let public = take_public(&mut inner);
+            let ident = inner.next().unwrap();
+            let ident = ident!(ident, h.clone())?;
+
+            let mut required = Vec::new();
+            let mut defaults = Vec::new();
+            for item in inner {
+                match item.as_rule() {
+                    Rule::fn_sig => required.push(
+                        item.into_inner()
+                            .find(|p| p.as_rule() == Rule::fn_outline)
+                            .unwrap()
+                            .into_inner()
+                            .find(|p| p.as_rule() == Rule::identifier)
+                            .unwrap()
+                            .as_str()
+                            .to_string(),
+                    ),
+                    _ => defaults.push(build_ast_from_expr(item, h.clone())?),
+                }
+            }
+
+            Ok(Node::TraitDeclaration {
+                public,
+                ident,
+                required,
+                defaults,
+            }
+            .provide_context(e.as_span()))
+        }
+
+        Rule::struct_inst => {
+            let mut inner = e.clone().into_inner();
+            let ident = inner.next().unwrap();
+            let ident = ident!(ident, h.clone())?;
+            let fields = inner
+                .map(|field| {
+                    let (name, expr) = takes!(field, 2);
+                    Ok((
+                        Node::Ident(ident!(name, h.clone())?).provide_context(name.as_span()),
+                        build!(expr, h),
+                    ))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+
+            Ok(Node::Struct { ident, fields }.provide_context(e.as_span()))
+        }
+
         Rule::fn_call => {
             let mut args = e.clone().into_inner().collect::<Vec<_>>();
             let ident = ident!(args.first().unwrap(), h.clone())?;
@@ -206,7 +525,7 @@ pub fn build_ast_from_expr<'a>(e: Pair<'a, Rule>, h: Hydrator) -> NodeRes<'a> {
             let mut body = e.clone().into_inner();
             let item = build!(body.next().unwrap(), h.clone());
             let rest = body
-                .map(|i| build_ast_from_expr(i, h.clone()))
+                .map(|i| build_postfix(i, h.clone()))
                 .collect::<Result<Vec<_>, _>>()?;
 
             Ok(Node::Index(Box::new(item), rest).provide_context(e.as_span()))
@@ -221,43 +540,26 @@ pub fn build_ast_from_expr<'a>(e: Pair<'a, Rule>, h: Hydrator) -> NodeRes<'a> {
         }
 
         Rule::lambda => {
-            // TODO: Typed inputs
-
-            let return_type = match has!(e.clone(), "typed") {
-                true => Some(e.clone().into_inner().last().unwrap().as_str().to_string()),
-                false => None,
-            };
+            let return_type = e
+                .clone()
+                .into_inner()
+                .find(|p| p.as_rule() == Rule::typed)
+                .map(typed_name);
 
+            // Untyped arguments take anything
             let args = e
                 .clone()
                 .into_inner()
-                .into_iter()
-                .take(
-                    e.clone().into_inner().into_iter().count()
-                        - match return_type.is_some() {
-                            true => 2,  // Block, and return type
-                            false => 1, // Block
-                        },
-                )
-                .map(|t| t.as_str().to_string())
+                .filter(|p| p.as_rule() == Rule::lambda_arg)
+                .map(|arg| {
+                    let mut inner = arg.into_inner();
+                    let ident = inner.next().unwrap().as_str().to_string();
+                    let typed = inner.next().map_or_else(|| "any".to_string(), typed_name);
+                    (ident, typed)
+                })
                 .collect::<Vec<_>>();
 
-            let body = e
-                .clone()
-                .into_inner()
-                .last()
-                .unwrap()
-                .into_inner()
-                .map(|t| build_ast_from_expr(t, h.clone()))
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|_| {
-                    partial!(
-                        "building lambda body",
-                        e.to_string(),
-                        e.as_span(),
-                        h.clone()
-                    )
-                })?;
+            let body = build_function_body(e.clone().into_inner().last().unwrap(), h)?;
 
             Ok(Node::Lambda {
                 args,
@@ -274,21 +576,19 @@ pub fn build_ast_from_expr<'a>(e: Pair<'a, Rule>, h: Hydrator) -> NodeRes<'a> {
     }
 }
 
-fn build_ast_from_term<'a>(t: Pair<'a, Rule>, h: Hydrator) -> NodeRes<'a> {
+fn build_ast_from_term(t: Pair<'static, Rule>, h: Hydrator) -> NodeRes {
     match t.as_rule() {
         Rule::expr => build_ast_from_expr(t.clone(), h).map(|e| e.0.clone()),
         Rule::identifier => Ok(Node::Ident(String::from(t.as_str()))),
 
-        Rule::string => Ok(Node::String(
-            t.as_str()[1..t.as_str().len() - 1].to_string(),
-        )),
+        Rule::string => build_string(t.clone(), h.clone()),
         Rule::boolean => Ok(Node::Bool(t.as_str().trim().parse::<bool>().map_err(
             |er| partial!("parsing boolean", er.to_string(), t.as_span(), h.clone()),
         )?)),
-        Rule::float => Ok(Node::Float(t.as_str().trim().parse::<f64>().map_err(
+        Rule::float => Ok(Node::Float(t.as_str().parse::<f64>().map_err(
             |er| partial!("parsing float", er.to_string(), t.as_span(), h.clone()),
         )?)),
-        Rule::int => Ok(Node::Int(t.as_str().trim().parse::<i128>().map_err(
+        Rule::int => Ok(Node::Int(t.as_str().parse::<i128>().map_err(
             |er| partial!("parsing integer", er.to_string(), t.as_span(), h.clone()),
         )?)),
         Rule::array => {
@@ -299,6 +599,24 @@ fn build_ast_from_term<'a>(t: Pair<'a, Rule>, h: Hydrator) -> NodeRes<'a> {
                 .collect::<Result<Vec<_>, _>>()?;
             Ok(Node::Array(elements))
         }
+        Rule::map => {
+            let entries = t
+                .clone()
+                .into_inner()
+                .map(|entry| {
+                    let (key, value) = takes!(entry, 2);
+                    let key = match key.as_rule() {
+                        // `{ name: 1 }` is keyed by the string "name"
+                        Rule::identifier => {
+                            Node::String(key.as_str().to_string()).provide_context(key.as_span())
+                        }
+                        _ => build!(key, h),
+                    };
+                    Ok((key, build!(value, h)))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            Ok(Node::Map(entries))
+        }
         Rule::null => Ok(Node::Null),
         _ => {
             eprintln!("{:?} not yet implemented", t.as_rule());
@@ -308,7 +626,265 @@ fn build_ast_from_term<'a>(t: Pair<'a, Rule>, h: Hydrator) -> NodeRes<'a> {
     .map(|n| n.provide_context(t.as_span()))
 }
 
-fn build_mondaic<'a>(pair: Pair<'a, Rule>, expr: ContextualNode<'a>, h: Hydrator) -> NodeRes<'a> {
+// Loops and the statements that leave them may carry an `'outer` label
+fn take_label(inner: &mut Peekable<Pairs<'_, Rule>>) -> Option<String> {
+    inner
+        .next_if(|p| p.as_rule() == Rule::label)
+        .map(|p| p.as_str().trim_start_matches('\'').to_string())
+}
+
+// Each `///` line becomes a line of documentation, without its marker
+fn doc_text(doc: Pair<'_, Rule>) -> String {
+    doc.into_inner()
+        .map(|line| {
+            let text = line.into_inner().next().unwrap().as_str();
+            text.strip_prefix(' ').unwrap_or(text).trim_end().to_string()
+        })
+        .join("\n")
+}
+
+// Items are private to their module unless marked `pub`
+fn take_public(inner: &mut Peekable<Pairs<'_, Rule>>) -> bool {
+    inner
+        .next_if(|p| p.as_rule() == Rule::visibility)
+        .is_some_and(|p| p.as_str() == "pub")
+}
+
+fn take_doc(inner: &mut Peekable<Pairs<'_, Rule>>) -> Option<String> {
+    inner.next_if(|p| p.as_rule() == Rule::doc).map(doc_text)
+}
+
+fn build_block(block: Pair<'static, Rule>, h: Hydrator) -> Result<Vec<ContextualNode>, Error> {
+    block
+        .into_inner()
+        .map(|t| build_ast_from_expr(t, h.clone()))
+        .try_collect()
+}
+
+// Functions that yield anywhere in their own body (not in functions nested
+// inside it) hand back a generator instead of running straight away
+fn build_function_body(
+    block: Pair<'static, Rule>,
+    h: Hydrator,
+) -> Result<Vec<ContextualNode>, Error> {
+    fn yields(pair: Pair<'_, Rule>) -> bool {
+        pair.into_inner().any(|p| match p.as_rule() {
+            Rule::yield_stmt => true,
+            Rule::fn_decl | Rule::lambda | Rule::async_block => false,
+            _ => yields(p),
+        })
+    }
+
+    let body = build_block(block.clone(), h)?;
+    Ok(match yields(block.clone()) {
+        true => vec![Node::Generator(body).provide_context(block.as_span())],
+        false => body,
+    })
+}
+
+// Named fields are looked up as-is, rather than evaluated
+fn build_postfix(i: Pair<'static, Rule>, h: Hydrator) -> NodeRes {
+    match i.as_rule() {
+        Rule::identifier => Ok(Node::String(i.as_str().to_string()).provide_context(i.as_span())),
+        _ => build_ast_from_expr(i, h),
+    }
+}
+
+// Plain strings stay plain, the ones with `${}` in them are put back together
+// when they're evaluated
+fn build_string(t: Pair<'static, Rule>, h: Hydrator) -> Result<Node, Error> {
+    let mut parts: Vec<ContextualNode> = Vec::new();
+    let mut text = String::new();
+    let mut text_start = None;
+
+    for part in t.clone().into_inner() {
+        match part.as_rule() {
+            Rule::raw_chars => text.push_str(part.as_str()),
+            Rule::escape => text.push(unescape(part.clone(), h.clone())?),
+            Rule::interpolation => {
+                if let Some(start) = text_start.take() {
+                    let span = Span::new(t.get_input(), start, part.as_span().start()).unwrap();
+                    parts.push(Node::String(std::mem::take(&mut text)).provide_context(span));
+                }
+                parts.push(build!(part.into_inner().next().unwrap(), h));
+                continue;
+            }
+            _ => {
+                return Err(partial!(
+                    "parsing string",
+                    "Unterminated interpolation",
+                    "Interpolations are closed with a `}`, as in \"${name}\"",
+                    Span::new(t.get_input(), part.as_span().start(), t.as_span().end()).unwrap(),
+                    h.clone()
+                ))
+            }
+        }
+        text_start.get_or_insert(part.as_span().start());
+    }
+
+    if parts.is_empty() {
+        return Ok(Node::String(text));
+    }
+
+    if let Some(start) = text_start {
+        let span = Span::new(t.get_input(), start, t.as_span().end() - 1).unwrap();
+        parts.push(Node::String(text).provide_context(span));
+    }
+
+    Ok(Node::Interpolated(parts))
+}
+
+fn unescape(escape: Pair<'_, Rule>, h: Hydrator) -> Result<char, Error> {
+    let code = &escape.as_str()[1..];
+    Ok(match code {
+        "n" => '\n',
+        "t" => '\t',
+        "r" => '\r',
+        "0" => '\0',
+        "b" => '\u{8}',
+        "f" => '\u{c}',
+        "\"" | "\\" | "/" | "$" => code.chars().next().unwrap(),
+        "u" => {
+            return Err(partial!(
+                "parsing string",
+                "Unicode escapes need four hex digits",
+                "As in \\u00e9",
+                escape.as_span(),
+                h.clone()
+            ))
+        }
+        _ if code.len() == 5 => u32::from_str_radix(&code[1..], 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or(partial!(
+                "parsing string",
+                format!("\\{code} isn't a valid character"),
+                escape.as_span(),
+                h.clone()
+            ))?,
+        _ => {
+            return Err(partial!(
+                "parsing string",
+                format!("Unknown escape \\{code}"),
+                "Try one of: \\n, \\t, \\r, \\0, \\b, \\f, \\\", \\\\, \\/, \\$, \\uXXXX",
+                escape.as_span(),
+                h.clone()
+            ))
+        }
+    })
+}
+
+// The name inside a `typed` pair, without the leading colon
+fn typed_name(t: Pair<'_, Rule>) -> String {
+    t.clone()
+        .into_inner()
+        .next()
+        .map(|i| i.as_str().to_string())
+        .unwrap_or_else(|| t.as_str().to_string())
+}
+
+fn build_pattern(p: Pair<'static, Rule>, h: Hydrator) -> Result<ContextualPattern, Error> {
+    let inner = p.clone().into_inner().next().unwrap();
+    let span = inner.as_span();
+
+    let pattern = match inner.as_rule() {
+        Rule::wildcard => Pattern::Wildcard,
+        Rule::identifier => Pattern::Binding(inner.as_str().to_string()),
+        Rule::pat_literal => {
+            let parts = inner.clone().into_inner().collect::<Vec<_>>();
+            let literal = build_ast_from_term(parts.last().unwrap().clone(), h.clone())?;
+
+            // Negative numbers are folded in here, so they compare like any other literal
+            let literal = match (parts.len(), literal.0) {
+                (1, node) => node,
+                (_, Node::Int(v)) => Node::Int(-v),
+                (_, Node::Float(v)) => Node::Float(-v),
+                _ => unreachable!(),
+            };
+
+            Pattern::Literal(Box::new(literal.provide_context(span)))
+        }
+        Rule::pat_array | Rule::pat_tuple => Pattern::Array(
+            inner
+                .into_inner()
+                .map(|i| build_pattern(i, h.clone()))
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        Rule::pat_struct => {
+            let (name, fields) = takes!(inner, 2);
+            Pattern::Struct(name.as_str().to_string(), build_pattern_fields(fields, h)?)
+        }
+        Rule::pat_variant => {
+            let mut parts = inner.into_inner();
+            let typed = parts.next().unwrap().as_str().to_string();
+            let variant = parts.next().unwrap();
+            let name = variant.as_str().to_string();
+
+            let payload = match parts.next() {
+                None => None,
+                Some(p) if p.as_rule() == Rule::pat_tuple => {
+                    let payload_span = p.as_span();
+                    Some(ContextualPattern(
+                        Pattern::Array(
+                            p.into_inner()
+                                .map(|i| build_pattern(i, h.clone()))
+                                .collect::<Result<Vec<_>, _>>()?,
+                        ),
+                        payload_span,
+                    ))
+                }
+                Some(p) => {
+                    let payload_span = p.as_span();
+                    Some(ContextualPattern(
+                        Pattern::Struct(name.clone(), build_pattern_fields(p, h)?),
+                        payload_span,
+                    ))
+                }
+            };
+
+            Pattern::Variant(typed, name, payload.map(Box::new))
+        }
+        _ => unreachable!(),
+    };
+
+    Ok(ContextualPattern(pattern, span))
+}
+
+// Fields without a pattern bind to a variable of the same name
+fn build_pattern_fields(
+    p: Pair<'static, Rule>,
+    h: Hydrator,
+) -> Result<Vec<(String, ContextualPattern)>, Error> {
+    p.into_inner()
+        .map(|field| {
+            let mut parts = field.clone().into_inner();
+            let name = parts.next().unwrap().as_str().to_string();
+            let pattern = match parts.next() {
+                Some(p) => build_pattern(p, h.clone())?,
+                None => ContextualPattern(Pattern::Binding(name.clone()), field.as_span()),
+            };
+
+            Ok((name, pattern))
+        })
+        .collect()
+}
+
+// Name and type pairs from a list of `typed_var`s
+fn typed_vars(t: Pair<'_, Rule>) -> Vec<(String, String)> {
+    t.into_inner()
+        .map(|n| match n.clone().into_inner().collect_tuple() {
+            Some((a, b)) => (a.as_str().to_string(), typed_name(b)),
+            None => (n.as_str().to_string(), n.as_str().to_string()),
+        })
+        .collect()
+}
+
+fn build_mondaic(
+    pair: Pair<'static, Rule>,
+    expr: ContextualNode,
+    span: Span<'static>,
+    h: Hydrator,
+) -> NodeRes {
     Ok(Node::MondaicOp {
         verb: get_mondaic(pair.as_str().to_string()).ok_or(partial!(
             "parsing mondaic",
@@ -319,15 +895,25 @@ fn build_mondaic<'a>(pair: Pair<'a, Rule>, expr: ContextualNode<'a>, h: Hydrator
         ))?,
         expr: Box::new(expr),
     }
-    .provide_context(pair.as_span()))
+    .provide_context(span))
 }
 
-fn build_dyadic<'a>(
-    pair: Pair<'a, Rule>,
-    lhs: ContextualNode<'a>,
-    rhs: ContextualNode<'a>,
+fn build_dyadic(
+    pair: Pair<'static, Rule>,
+    lhs: ContextualNode,
+    rhs: ContextualNode,
     h: Hydrator,
-) -> NodeRes<'a> {
+) -> NodeRes {
+    let span = extend(&[lhs.1, rhs.1]);
+    if matches!(pair.as_rule(), Rule::range_inclusive | Rule::range_exclusive) {
+        return Ok(Node::Range {
+            start: Box::new(lhs),
+            end: Box::new(rhs),
+            inclusive: pair.as_rule() == Rule::range_inclusive,
+        }
+        .provide_context(span));
+    }
+
     Ok(Node::DyadicOp {
         verb: get_dyadic(pair.as_str().to_string()).ok_or(partial!(
             "parsing dyadic",
@@ -339,5 +925,5 @@ fn build_dyadic<'a>(
         lhs: Box::new(lhs),
         rhs: Box::new(rhs),
     }
-    .provide_context(pair.as_span()))
+    .provide_context(span))
 }
@@ -0,0 +1,869 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use pest::Span;
+
+use crate::{
+    ast::{
+        op::{Dyadic, Mondaic},
+        ContextualNode, ContextualPattern, Node, Pattern,
+    },
+    errors::{Error, Hydrator},
+    helpers::extend,
+};
+
+/// What the checker knows about a value before it runs. Anything it can't
+/// work out is `Any`, which fits everywhere, so only mismatches it's sure of
+/// are reported.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    Any,
+    Int,
+    Float,
+    Bool,
+    String,
+    Null,
+    Array,
+    Map,
+    Range,
+    Function,
+    Named(String),
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Type::Any => "any",
+            Type::Int => "int",
+            Type::Float => "float",
+            Type::Bool => "bool",
+            Type::String => "string",
+            Type::Null => "null",
+            Type::Array => "array",
+            Type::Map => "map",
+            Type::Range => "range",
+            Type::Function => "function",
+            Type::Named(name) => name,
+        })
+    }
+}
+
+impl Type {
+    /// Whether a value of this type can be used where `expected` is wanted.
+    /// Ints are allowed to stand in for floats.
+    pub fn fits(&self, expected: &Type) -> bool {
+        match (self, expected) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Int, Type::Float) => true,
+            (a, b) => a == b,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Type::Int | Type::Float)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Signature {
+    name: String,
+    params: Vec<(String, Type)>,
+    ret: Type,
+}
+
+#[derive(Clone, Debug)]
+struct Binding {
+    typed: Type,
+    annotated: bool,
+    signature: Option<Signature>,
+}
+
+/// Walks a program's tree ahead of evaluation, inferring what it can and
+/// checking it against the type annotations.
+pub struct Checker {
+    h: Hydrator,
+    scopes: Vec<BTreeMap<String, Binding>>,
+    structs: BTreeMap<String, Vec<(String, String)>>,
+    enums: Vec<String>,
+    methods: BTreeMap<(String, String), Signature>,
+    // The function being checked, and what it should return
+    returns: Vec<(String, Type)>,
+    self_type: Vec<Type>,
+    errors: Vec<Error>,
+}
+
+/// Checks a whole program, returning every mismatch found rather than
+/// stopping at the first.
+pub fn check(tree: &[ContextualNode], h: Hydrator) -> Vec<Error> {
+    let mut checker = Checker {
+        h,
+        scopes: vec![BTreeMap::new()],
+        structs: BTreeMap::new(),
+        enums: vec![],
+        methods: BTreeMap::new(),
+        returns: vec![],
+        self_type: vec![],
+        errors: vec![],
+    };
+
+    checker.block(tree);
+    checker.errors
+}
+
+impl Checker {
+    fn report(&mut self, label: String, hint: Option<String>, span: Span<'_>) {
+        let h = self.h.clone();
+        self.errors.push(match hint {
+            Some(hint) => partial!("checking types", label, hint, span, h),
+            None => partial!("checking types", label, span, h),
+        });
+    }
+
+    fn expect(&mut self, actual: &Type, expected: &Type, hint: String, span: Span<'_>) {
+        if !actual.fits(expected) {
+            self.report(
+                format!("Expected {expected}, found {actual}"),
+                Some(hint),
+                span,
+            );
+        }
+    }
+
+    fn resolve(&self, annotation: &str) -> Type {
+        match annotation {
+            "int" => Type::Int,
+            "float" => Type::Float,
+            "bool" => Type::Bool,
+            "string" => Type::String,
+            "null" => Type::Null,
+            "array" => Type::Array,
+            "map" => Type::Map,
+            "range" => Type::Range,
+            "function" | "lambda" => Type::Function,
+            name if self.structs.contains_key(name) || self.enums.iter().any(|e| e == name) => {
+                Type::Named(name.to_string())
+            }
+            // `any`, traits, and anything we haven't seen declared
+            _ => Type::Any,
+        }
+    }
+
+    fn bind(&mut self, ident: &str, binding: Binding) {
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(ident.to_string(), binding);
+    }
+
+    fn lookup(&self, ident: &str) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|s| s.get(ident))
+    }
+
+    fn lookup_mut(&mut self, ident: &str) -> Option<&mut Binding> {
+        self.scopes.iter_mut().rev().find_map(|s| s.get_mut(ident))
+    }
+
+    fn signature(&self, name: &str, args: &[(String, String)], ret: &Option<String>) -> Signature {
+        Signature {
+            name: name.to_string(),
+            params: args
+                .iter()
+                .filter(|(arg, _)| arg != "self")
+                .map(|(arg, typed)| (arg.clone(), self.resolve(typed)))
+                .collect(),
+            ret: ret.as_deref().map_or(Type::Any, |r| self.resolve(r)),
+        }
+    }
+
+    fn scoped<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.scopes.push(BTreeMap::new());
+        let result = f(self);
+        self.scopes.pop();
+        result
+    }
+
+    // Declarations are hoisted the same way `step_block` does it, types
+    // first so that functions can be annotated with them
+    fn hoist(&mut self, body: &[ContextualNode]) {
+        for node in body {
+            match &node.0 {
+                Node::StructDeclaration { ident, fields, .. } => {
+                    self.structs.insert(ident.clone(), fields.clone());
+                }
+                Node::EnumDeclaration { ident, .. } => self.enums.push(ident.clone()),
+                _ => {}
+            }
+        }
+
+        for node in body {
+            match &node.0 {
+                Node::FunctionDeclaration {
+                    ident,
+                    args,
+                    return_type,
+                    ..
+                } => {
+                    let signature = self.signature(ident, args, return_type);
+                    self.bind(
+                        ident,
+                        Binding {
+                            typed: Type::Function,
+                            annotated: true,
+                            signature: Some(signature),
+                        },
+                    );
+                }
+                Node::Impl { typed, methods, .. } => {
+                    for method in methods {
+                        if let Node::FunctionDeclaration {
+                            ident,
+                            args,
+                            return_type,
+                            ..
+                        } = &method.0
+                        {
+                            let mut signature = self.signature(ident, args, return_type);
+                            signature.name = format!("{typed}.{ident}");
+                            self.methods
+                                .insert((typed.clone(), ident.clone()), signature);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn block(&mut self, body: &[ContextualNode]) -> Type {
+        self.hoist(body);
+        body.iter().fold(Type::Null, |_, node| self.infer(node))
+    }
+
+    fn function(
+        &mut self,
+        name: &str,
+        args: &[(String, Type)],
+        ret: Type,
+        body: &[ContextualNode],
+    ) {
+        self.returns.push((name.to_string(), ret));
+        self.scoped(|c| {
+            for (arg, typed) in args {
+                let typed = match arg.as_str() {
+                    "self" => c.self_type.last().cloned().unwrap_or(Type::Any),
+                    _ => typed.clone(),
+                };
+
+                c.bind(
+                    arg,
+                    Binding {
+                        typed,
+                        annotated: true,
+                        signature: None,
+                    },
+                );
+            }
+            c.block(body);
+        });
+        self.returns.pop();
+    }
+
+    fn condition(&mut self, cond: &ContextualNode) {
+        let typed = self.infer(cond);
+        self.expect(
+            &typed,
+            &Type::Bool,
+            "Conditions have to be bools".to_string(),
+            cond.span(),
+        );
+    }
+
+    fn call(&mut self, signature: &Signature, args: &[ContextualNode], span: Span<'_>) -> Type {
+        let typed = args.iter().map(|a| self.infer(a)).collect::<Vec<_>>();
+
+        if typed.len() != signature.params.len() {
+            self.report(
+                format!(
+                    "{} takes {} arguments, but {} were given",
+                    signature.name,
+                    signature.params.len(),
+                    typed.len()
+                ),
+                None,
+                span,
+            );
+            return signature.ret.clone();
+        }
+
+        for ((arg, actual), (param, expected)) in args.iter().zip(typed).zip(&signature.params) {
+            self.expect(
+                &actual,
+                expected,
+                format!("{param} is declared as {expected} in {}", signature.name),
+                arg.span(),
+            );
+        }
+
+        signature.ret.clone()
+    }
+
+    fn bind_pattern(&mut self, pattern: &ContextualPattern, typed: Type) {
+        match &pattern.0 {
+            Pattern::Binding(name) => self.bind(
+                name,
+                Binding {
+                    typed,
+                    annotated: false,
+                    signature: None,
+                },
+            ),
+            Pattern::Array(patterns) => patterns
+                .iter()
+                .for_each(|p| self.bind_pattern(p, Type::Any)),
+            Pattern::Struct(_, fields) => fields
+                .iter()
+                .for_each(|(_, p)| self.bind_pattern(p, Type::Any)),
+            Pattern::Variant(_, _, Some(inner)) => self.bind_pattern(inner, Type::Any),
+            Pattern::Literal(literal) => {
+                self.infer(literal);
+            }
+            Pattern::Wildcard | Pattern::Variant(_, _, None) => {}
+        }
+    }
+
+    fn infer(&mut self, node: &ContextualNode) -> Type {
+        match &node.0 {
+            Node::Int(_) => Type::Int,
+            Node::Float(_) => Type::Float,
+            Node::Bool(_) => Type::Bool,
+            Node::String(_) => Type::String,
+            Node::Null | Node::Doc(_) | Node::Continue { .. } => Type::Null,
+
+            Node::Interpolated(parts) => {
+                parts.iter().for_each(|p| {
+                    self.infer(p);
+                });
+                Type::String
+            }
+
+            Node::Array(items) => {
+                items.iter().for_each(|i| {
+                    self.infer(i);
+                });
+                Type::Array
+            }
+
+            Node::Map(entries) => {
+                for (key, value) in entries {
+                    // Bare keys are names, not variables
+                    if !matches!(key.0, Node::Ident(_)) {
+                        self.infer(key);
+                    }
+                    self.infer(value);
+                }
+                Type::Map
+            }
+
+            Node::Terms(terms) => terms.iter().fold(Type::Any, |_, t| self.infer(t)),
+
+            Node::Ident(ident) => self.lookup(ident).map_or(Type::Any, |b| b.typed.clone()),
+
+            Node::MondaicOp { verb, expr } => {
+                let typed = self.infer(expr);
+                let result = match verb {
+                    Mondaic::Negate => Type::Bool,
+                    Mondaic::Negative if typed.is_numeric() => typed.clone(),
+                    Mondaic::Negative => Type::Any,
+                    Mondaic::BitNot => Type::Int,
+                };
+
+                let fits = match verb {
+                    Mondaic::Negate => typed.fits(&Type::Bool),
+                    Mondaic::Negative => typed.fits(&Type::Float),
+                    Mondaic::BitNot => typed.fits(&Type::Int),
+                };
+
+                if !fits {
+                    self.report(
+                        format!("can't use verb {} on type {typed}", verb.to_symbol()),
+                        None,
+                        node.span(),
+                    );
+                }
+
+                result
+            }
+
+            Node::DyadicOp { verb, lhs, rhs } => {
+                let left = self.infer(lhs);
+                let right = self.infer(rhs);
+                self.dyad(*verb, left, right, lhs, rhs)
+            }
+
+            Node::Range { start, end, .. } => {
+                for bound in [start, end] {
+                    let typed = self.infer(bound);
+                    self.expect(
+                        &typed,
+                        &Type::Int,
+                        "Ranges go between ints".to_string(),
+                        bound.span(),
+                    );
+                }
+                Type::Range
+            }
+
+            Node::Delclaration {
+                ident, typed, expr, ..
+            } => {
+                let actual = self.infer(expr);
+                let signature = match &expr.0 {
+                    Node::Lambda {
+                        args, return_type, ..
+                    } => Some(Signature {
+                        name: ident.clone(),
+                        params: args
+                            .iter()
+                            .map(|(arg, typed)| (arg.clone(), self.resolve(typed)))
+                            .collect(),
+                        ret: return_type.as_deref().map_or(Type::Any, |r| self.resolve(r)),
+                    }),
+                    _ => None,
+                };
+
+                let binding = match typed {
+                    Some(annotation) => {
+                        let expected = self.resolve(annotation);
+                        self.expect(
+                            &actual,
+                            &expected,
+                            format!("{ident} is declared as {expected}"),
+                            expr.span(),
+                        );
+                        Binding {
+                            typed: expected,
+                            annotated: true,
+                            signature,
+                        }
+                    }
+                    None => Binding {
+                        typed: actual,
+                        annotated: false,
+                        signature,
+                    },
+                };
+
+                let typed = binding.typed.clone();
+                self.bind(ident, binding);
+                typed
+            }
+
+            Node::Assignment { ident, path, expr, .. } => {
+                path.iter().for_each(|p| {
+                    if !matches!(p.0, Node::String(_)) {
+                        self.infer(p);
+                    }
+                });
+                let actual = self.infer(expr);
+
+                // Only the variable itself is checked, not what's inside it
+                if !path.is_empty() {
+                    return actual;
+                }
+
+                let expected = match self.lookup_mut(ident) {
+                    Some(b) if b.annotated => b.typed.clone(),
+                    // Unannotated variables can hold anything over their life
+                    Some(b) => {
+                        if b.typed != actual {
+                            b.typed = Type::Any;
+                            b.signature = None;
+                        }
+                        return actual;
+                    }
+                    None => return actual,
+                };
+
+                self.expect(
+                    &actual,
+                    &expected,
+                    format!("{ident} is declared as {expected}"),
+                    expr.span(),
+                );
+                actual
+            }
+
+            Node::Return(expr) => {
+                let actual = self.infer(expr);
+                if let Some((name, expected)) = self.returns.last().cloned() {
+                    self.expect(
+                        &actual,
+                        &expected,
+                        format!("{name} returns {expected}"),
+                        expr.span(),
+                    );
+                }
+                actual
+            }
+
+            Node::Conditional { arms, else_arm } => {
+                for (cond, body) in arms {
+                    self.condition(cond);
+                    self.scoped(|c| c.block(body));
+                }
+                if let Some(body) = else_arm {
+                    self.scoped(|c| c.block(body));
+                }
+                Type::Null
+            }
+
+            Node::LoopWhile {
+                condition, body, ..
+            } => {
+                self.condition(condition);
+                self.scoped(|c| c.block(body));
+                Type::Any
+            }
+
+            Node::Loop { body, .. } => {
+                self.scoped(|c| c.block(body));
+                Type::Any
+            }
+
+            Node::LoopFor {
+                pattern,
+                iterable,
+                body,
+                ..
+            } => {
+                let typed = self.infer(iterable);
+                let item = match typed {
+                    Type::Range => Type::Int,
+                    Type::String => Type::String,
+                    Type::Int | Type::Float | Type::Bool | Type::Null | Type::Function => {
+                        self.report(
+                            format!("Can't iterate over type {typed}"),
+                            None,
+                            iterable.span(),
+                        );
+                        Type::Any
+                    }
+                    _ => Type::Any,
+                };
+
+                self.scoped(|c| {
+                    c.bind_pattern(pattern, item);
+                    c.block(body);
+                });
+                Type::Any
+            }
+
+            Node::Yield(expr) => {
+                if let Some(expr) = expr {
+                    self.infer(expr);
+                }
+                Type::Null
+            }
+
+            // Returns only end a generator, so they aren't held to the return type
+            Node::Generator(body) => {
+                self.returns.push(("the generator".to_string(), Type::Any));
+                self.scoped(|c| c.block(body));
+                self.returns.pop();
+                Type::Any
+            }
+
+            // Returns in a task settle its promise, so they're held to what it promised
+            Node::Async { typed, body } => {
+                let promised = self.resolve(typed);
+                self.returns.push(("the task".to_string(), promised));
+                self.scoped(|c| c.block(body));
+                self.returns.pop();
+                Type::Any
+            }
+
+            Node::Await(expr) => {
+                self.infer(expr);
+                Type::Any
+            }
+
+            Node::Throw(expr) => {
+                self.infer(expr);
+                Type::Null
+            }
+
+            Node::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.scoped(|c| c.block(body));
+                if let Some((binding, handler)) = catch {
+                    self.scoped(|c| {
+                        if let Some(binding) = binding {
+                            c.bind(
+                                binding,
+                                Binding {
+                                    typed: Type::Any,
+                                    annotated: false,
+                                    signature: None,
+                                },
+                            );
+                        }
+                        c.block(handler);
+                    });
+                }
+                if let Some(finally) = finally {
+                    self.scoped(|c| c.block(finally));
+                }
+                Type::Null
+            }
+
+            Node::Break { expr, .. } => {
+                if let Some(expr) = expr {
+                    self.infer(expr);
+                }
+                Type::Null
+            }
+
+            Node::FunctionDeclaration {
+                ident,
+                args,
+                return_type,
+                body,
+                ..
+            } => {
+                let signature = self.signature(ident, args, return_type);
+                let args = args
+                    .iter()
+                    .map(|(arg, typed)| (arg.clone(), self.resolve(typed)))
+                    .collect::<Vec<_>>();
+                self.function(ident, &args, signature.ret, body);
+                Type::Function
+            }
+
+            Node::Lambda {
+                args,
+                return_type,
+                body,
+            } => {
+                let args = args
+                    .iter()
+                    .map(|(arg, typed)| (arg.clone(), self.resolve(typed)))
+                    .collect::<Vec<_>>();
+                let ret = return_type.as_deref().map_or(Type::Any, |r| self.resolve(r));
+                self.function("the lambda", &args, ret, body);
+                Type::Function
+            }
+
+            Node::FunctionCall { ident, args } => {
+                match self.lookup(ident).and_then(|b| b.signature.clone()) {
+                    Some(signature) => self.call(&signature, args, node.span()),
+                    None => {
+                        args.iter().for_each(|a| {
+                            self.infer(a);
+                        });
+                        Type::Any
+                    }
+                }
+            }
+
+            Node::Struct { ident, fields } => {
+                let declared = self.structs.get(ident).cloned();
+                for (name, value) in fields {
+                    let actual = self.infer(value);
+                    let Node::Ident(name) = &name.0 else {
+                        continue;
+                    };
+
+                    let expected = declared
+                        .iter()
+                        .flatten()
+                        .find(|(field, _)| field == name)
+                        .map(|(_, typed)| self.resolve(typed));
+
+                    if let Some(expected) = expected {
+                        self.expect(
+                            &actual,
+                            &expected,
+                            format!("Field {name} of {ident} is declared as {expected}"),
+                            value.span(),
+                        );
+                    }
+                }
+
+                match declared {
+                    Some(_) => Type::Named(ident.clone()),
+                    None => Type::Any,
+                }
+            }
+
+            Node::Index(item, postfix) => {
+                let mut typed = self.infer(item);
+                for part in postfix {
+                    typed = match (&typed, &part.0) {
+                        (Type::Named(name), Node::String(field)) => self
+                            .structs
+                            .get(name)
+                            .and_then(|fields| fields.iter().find(|(f, _)| f == field))
+                            .map_or(Type::Any, |(_, t)| self.resolve(t)),
+                        (Type::Named(name), Node::FunctionCall { ident, args }) => {
+                            match self.methods.get(&(name.clone(), ident.clone())).cloned() {
+                                Some(signature) => self.call(&signature, args, part.span()),
+                                None => self.infer(part),
+                            }
+                        }
+                        (_, Node::String(_)) => Type::Any,
+                        (_, Node::FunctionCall { args, .. }) => {
+                            args.iter().for_each(|a| {
+                                self.infer(a);
+                            });
+                            Type::Any
+                        }
+                        _ => {
+                            self.infer(part);
+                            Type::Any
+                        }
+                    };
+                }
+                typed
+            }
+
+            Node::Match { expr, arms } => {
+                self.infer(expr);
+                for (pattern, guard, body) in arms {
+                    self.scoped(|c| {
+                        c.bind_pattern(pattern, Type::Any);
+                        if let Some(guard) = guard {
+                            c.condition(guard);
+                        }
+                        c.block(body);
+                    });
+                }
+                Type::Any
+            }
+
+            Node::Impl { typed, methods, .. } => {
+                let slf = self.resolve(typed);
+                self.self_type.push(slf);
+                methods.iter().for_each(|m| {
+                    self.infer(m);
+                });
+                self.self_type.pop();
+                Type::Null
+            }
+
+            Node::TraitDeclaration { defaults, .. } => {
+                self.self_type.push(Type::Any);
+                defaults.iter().for_each(|d| {
+                    self.infer(d);
+                });
+                self.self_type.pop();
+                Type::Null
+            }
+
+            Node::Import { alias, names, .. } => {
+                let imported = alias.iter().cloned().chain(names.iter().filter_map(|n| match &n.0 {
+                    Node::Ident(name) => Some(name.clone()),
+                    _ => None,
+                }));
+
+                for name in imported.collect::<Vec<_>>() {
+                    self.bind(
+                        &name,
+                        Binding {
+                            typed: Type::Any,
+                            annotated: false,
+                            signature: None,
+                        },
+                    );
+                }
+                Type::Null
+            }
+
+            Node::StructDeclaration { .. } | Node::EnumDeclaration { .. } => Type::Null,
+        }
+    }
+
+    fn dyad(
+        &mut self,
+        verb: Dyadic,
+        left: Type,
+        right: Type,
+        lhs: &ContextualNode,
+        rhs: &ContextualNode,
+    ) -> Type {
+        use Dyadic::*;
+
+        let comparison = matches!(
+            verb,
+            GreaterThan | LessThan | GreaterThanOrEqual | LessThanOrEqual
+        );
+
+        match verb {
+            Equality | NotEqual => return Type::Bool,
+            Coalesce => {
+                return match (&left, &right) {
+                    (Type::Null, _) => right,
+                    (l, r) if l == r => left,
+                    _ => Type::Any,
+                }
+            }
+            And | Or => {
+                for (typed, side) in [(&left, lhs), (&right, rhs)] {
+                    if !typed.fits(&Type::Bool) {
+                        self.report(
+                            format!("can't use verb {} on type {typed}", verb.to_symbol()),
+                            None,
+                            side.span(),
+                        );
+                    }
+                }
+                return Type::Bool;
+            }
+            _ => {}
+        }
+
+        // Without both sides there's nothing to be sure of
+        if left == Type::Any || right == Type::Any {
+            return match comparison {
+                true => Type::Bool,
+                false => Type::Any,
+            };
+        }
+
+        let span = extend(&[lhs.span(), rhs.span()]);
+        let result = match (&left, &right) {
+            (Type::String, _) | (_, Type::String) => match verb {
+                Add => Some(Type::String),
+                _ => None,
+            },
+            (Type::Int, Type::Int) => match verb {
+                _ if comparison => Some(Type::Bool),
+                _ => Some(Type::Int),
+            },
+            (a, b) if a.is_numeric() && b.is_numeric() => match verb {
+                _ if comparison => Some(Type::Bool),
+                IntDivide => Some(Type::Int),
+                Add | Subtract | Multiply | Divide | Modulo | Pow => Some(Type::Float),
+                _ => None,
+            },
+            (Type::Bool, Type::Bool) => match verb {
+                BitAnd | BitOr | BitXor => Some(Type::Bool),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        result.unwrap_or_else(|| {
+            let label = match left == right || left.fits(&right) || right.fits(&left) {
+                true => format!("can't use verb {} on type {left}", verb.to_symbol()),
+                false => format!(
+                    "can't use verb {} on types {left} and {right}",
+                    verb.to_symbol()
+                ),
+            };
+            self.report(label, None, span);
+            Type::Any
+        })
+    }
+}
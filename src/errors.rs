@@ -1,12 +1,24 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+    fmt::Display,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
 
 use miette::{Diagnostic, LabeledSpan, NamedSource, SourceOffset, SourceSpan};
+use pest::Span;
+
+use crate::object::ContextualObject;
 
 // Path, Content
 pub type Hydrator = (String, Arc<String>);
 
-#[derive(Debug)]
-pub struct Error {
+/// A failure in a petal program. The details are boxed, so that the results
+/// passed around the evaluator stay small.
+#[derive(Debug, Clone)]
+pub struct Error(pub Box<Details>);
+
+#[derive(Debug, Clone)]
+pub struct Details {
     pub during_process: String,
     pub error: String,
     pub hint: Option<String>,
@@ -17,6 +29,96 @@ pub struct Error {
 
     pub position: (usize, usize),
     pub length: usize,
+
+    // What a `throw` threw, as opposed to a failure inside petal itself
+    pub thrown: Option<Box<ContextualObject>>,
+
+    // The calls this error unwound through, innermost first
+    pub trace: Vec<Frame>,
+}
+
+/// A call that an error unwound through, shown beneath it as a traceback.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub function: String,
+
+    pub source: NamedSource<String>,
+    pub source_code: String,
+
+    pub position: (usize, usize),
+    pub length: usize,
+}
+
+impl Deref for Error {
+    type Target = Details;
+
+    fn deref(&self) -> &Details {
+        &self.0
+    }
+}
+
+impl DerefMut for Error {
+    fn deref_mut(&mut self) -> &mut Details {
+        &mut self.0
+    }
+}
+
+impl Error {
+    /// Records that the error came out of a call to `function` at `span`.
+    /// Calls that the error already points inside of don't add anything.
+    pub fn called_from(mut self, function: &str, span: Span, h: Hydrator) -> Self {
+        let (start, end) = (span.start_pos().line_col(), span.end_pos().line_col());
+        let inside = self.trace.is_empty()
+            && self.source_path == h.0
+            && start <= self.position
+            && self.position < end;
+
+        if !inside {
+            self.trace.push(Frame {
+                function: function.to_string(),
+                source: make_source(&h),
+                source_code: h.1.to_string(),
+                position: start,
+                length: span.end() - span.start(),
+            });
+        }
+        self
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "in {}", self.function)
+    }
+}
+
+impl std::error::Error for Frame {}
+
+impl Diagnostic for Frame {
+    fn severity(&self) -> Option<miette::Severity> {
+        Some(miette::Severity::Advice)
+    }
+
+    fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+        Some(&self.source)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = miette::LabeledSpan> + '_>> {
+        Some(Box::new(
+            [LabeledSpan::new_primary_with_span(
+                Some(format!("{} was called here", self.function)),
+                SourceSpan::new(
+                    SourceOffset::from_location(
+                        &self.source_code,
+                        self.position.0,
+                        self.position.1,
+                    ),
+                    self.length,
+                ),
+            )]
+            .into_iter(),
+        ))
+    }
 }
 
 impl Display for Error {
@@ -76,7 +178,10 @@ impl Diagnostic for Error {
     }
 
     fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
-        None
+        match self.trace.is_empty() {
+            true => None,
+            false => Some(Box::new(self.trace.iter().map(|f| f as &dyn Diagnostic))),
+        }
     }
 
     fn diagnostic_source(&self) -> Option<&dyn Diagnostic> {
@@ -90,7 +195,7 @@ pub fn make_source(h: &Hydrator) -> NamedSource<String> {
 
 macro_rules! partial {
     ($during:expr, $label:expr, $hint:expr, $span:expr, $h:expr) => {
-        Error {
+        Error(Box::new(crate::errors::Details {
             during_process: format!("while {}", $during),
             error: $label.to_string(),
             hint: Some($hint.to_string()),
@@ -101,10 +206,12 @@ macro_rules! partial {
 
             position: $span.start_pos().line_col(),
             length: $span.end() - $span.start(),
-        }
+            thrown: None,
+            trace: Vec::new(),
+        }))
     };
     ($during:expr, $label:expr, $span:expr, $h:expr) => {
-        Error {
+        Error(Box::new(crate::errors::Details {
             during_process: format!("while {}", $during),
             error: $label.to_string(),
             hint: None,
@@ -115,6 +222,8 @@ macro_rules! partial {
 
             position: $span.start_pos().line_col(),
             length: $span.end() - $span.start(),
-        }
+            thrown: None,
+            trace: Vec::new(),
+        }))
     };
 }
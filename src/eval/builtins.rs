@@ -1,10 +1,10 @@
-use std::{collections::BTreeMap, ops::RangeInclusive};
+use std::{collections::BTreeMap, ops::RangeInclusive, thread, time::Duration};
 
 use crate::{
-    errors::{Error, Hydrator}, eval::repl::ReplDisplay, helpers::extend, object::{ContextualObject, Object}, scope::MutScope, types::Num
+    errors::{Error, Hydrator}, eval::{intrinsics::{callback, count}, isolates::{self, Channel}, repl::ReplDisplay, tasks}, helpers::extend, object::{ContextualObject, Object}, scope::MutScope, types::Num
 };
 
-pub static BUILTINS: &[&str] = &[];
+pub static BUILTINS: &[&str] = &["spawn", "chan"];
 
 macro_rules! map {
     ([$($name:expr),+ $(,)?]) => {
@@ -23,11 +23,15 @@ macro_rules! map {
     };
 }
 
-pub fn get_builtin<'a>(ident: &str) -> Option<ContextualObject<'a>> {
+pub fn get_builtin(ident: &str) -> Option<ContextualObject> {
     Some(
         match ident {
             "term" => map!([print, clear]),
             "process" => map!([exit]),
+            "promise" => map!([all, race, any]),
+            "time" => map!([sleep, set_timeout, set_interval]),
+            "spawn" => Object::Builtin(ident.to_string(), false, spawn),
+            "chan" => Object::Builtin(ident.to_string(), false, chan),
             _ => return None,
         }
         .anonymous(),
@@ -36,7 +40,7 @@ pub fn get_builtin<'a>(ident: &str) -> Option<ContextualObject<'a>> {
 
 //
 
-fn exit<'a>(a: Vec<ContextualObject<'a>>, h: Hydrator, _: MutScope<'a>) -> Result<ContextualObject<'a>, Error> {
+fn exit(a: Vec<ContextualObject>, h: Hydrator, _: MutScope) -> Result<ContextualObject, Error> {
     assert_args_range(&a, 0..=1, h.clone())?;
     std::process::exit(
         match &a
@@ -59,7 +63,7 @@ fn exit<'a>(a: Vec<ContextualObject<'a>>, h: Hydrator, _: MutScope<'a>) -> Resul
 
 //
 
-fn print<'a>(a: Vec<ContextualObject<'a>>, _: Hydrator, _: MutScope<'a>) -> Result<ContextualObject<'a>, Error> {
+fn print(a: Vec<ContextualObject>, _: Hydrator, _: MutScope) -> Result<ContextualObject, Error> {
     println!(
         "{}",
         a.iter()
@@ -71,15 +75,161 @@ fn print<'a>(a: Vec<ContextualObject<'a>>, _: Hydrator, _: MutScope<'a>) -> Resu
     Ok(Object::Null.anonymous())
 }
 
-fn clear<'a>(_: Vec<ContextualObject<'a>>, _: Hydrator, _: MutScope<'a>) -> Result<ContextualObject<'a>, Error> {
+fn clear(_: Vec<ContextualObject>, _: Hydrator, _: MutScope) -> Result<ContextualObject, Error> {
     print!("\x1B[2J\x1B[1;1H");
     Ok(Object::Null.anonymous())
 }
 
 //
 
-pub fn assert_args_len<'a>(
-    args: &Vec<ContextualObject<'a>>,
+fn all(a: Vec<ContextualObject>, h: Hydrator, _: MutScope) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    let ids = promises(&a[0], h)?;
+
+    // Settles as soon as anything fails, rather than waiting for the rest
+    Ok(tasks::queue_native(move || {
+        let mut results = vec![None; ids.len()];
+        let mut pending = (0..ids.len()).collect::<Vec<_>>();
+        while !pending.is_empty() {
+            let waiting = pending.iter().map(|i| ids[*i].clone()).collect::<Vec<_>>();
+            let (index, result) = tasks::wait_any(&waiting);
+            results[pending.remove(index)] = Some(result?);
+        }
+
+        Ok(Object::Array(results.into_iter().flatten().collect()).anonymous())
+    })
+    .anonymous())
+}
+
+fn race(a: Vec<ContextualObject>, h: Hydrator, _: MutScope) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    let ids = some_promises(&a[0], h)?;
+    Ok(tasks::queue_native(move || tasks::wait_any(&ids).1).anonymous())
+}
+
+fn any(a: Vec<ContextualObject>, h: Hydrator, _: MutScope) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    let mut pending = some_promises(&a[0], h.clone())?;
+    let span = a[0].1;
+
+    Ok(tasks::queue_native(move || {
+        let mut first = None;
+        while !pending.is_empty() {
+            let (index, result) = tasks::wait_any(&pending);
+            pending.remove(index);
+            match result {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    first.get_or_insert(e);
+                }
+            }
+        }
+
+        Err(partial!(
+            "awaiting",
+            "Every promise failed",
+            format!("The first to fail said: {}", first.unwrap().error),
+            span,
+            h
+        ))
+    })
+    .anonymous())
+}
+
+// Awaiting a sleep lets other tasks get on in the meantime, rather than
+// holding up the thread
+fn sleep(a: Vec<ContextualObject>, h: Hydrator, _: MutScope) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    let delay = Duration::from_millis(count(&a[0], h)? as u64);
+
+    if tasks::is_awaited() {
+        return Ok(tasks::sleep(delay).anonymous());
+    }
+    thread::sleep(delay);
+    Ok(Object::Null.anonymous())
+}
+
+fn set_timeout(a: Vec<ContextualObject>, h: Hydrator, s: MutScope) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 2, h.clone())?;
+    let f = callback(&a[0], "set a timeout", h.clone())?;
+    let delay = Duration::from_millis(count(&a[1], h.clone())? as u64);
+
+    Ok(tasks::set_timeout(delay, Box::new(move || f.call(vec![], s, h))).anonymous())
+}
+
+fn set_interval(a: Vec<ContextualObject>, h: Hydrator, s: MutScope) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 2, h.clone())?;
+    let f = callback(&a[0], "set an interval", h.clone())?;
+    let period = Duration::from_millis(count(&a[1], h.clone())?.max(1) as u64);
+
+    Ok(tasks::set_interval(period, move || f.call(vec![], s.clone(), h.clone())).anonymous())
+}
+
+// Isolates only share what's sent through channels
+fn spawn(a: Vec<ContextualObject>, h: Hydrator, _: MutScope) -> Result<ContextualObject, Error> {
+    assert_args_range(&a, 1..=2, h.clone())?;
+    let f = callback(&a[0], "spawn", h.clone())?;
+    let args = match a.get(1).map(|args| &args.0) {
+        Some(Object::Array(args)) => args.clone(),
+        Some(other) => {
+            return Err(partial!(
+                "checking types",
+                format!("Expected an array of arguments, found {}", other.typed()),
+                a[1].1,
+                h
+            ))
+        }
+        None => vec![],
+    };
+
+    Ok(isolates::spawn(&f, &args, a[0].1, h)?.anonymous())
+}
+
+fn chan(a: Vec<ContextualObject>, h: Hydrator, _: MutScope) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 0, h)?;
+    Ok(Object::Channel(Channel::new()).anonymous())
+}
+
+// The ids of an array of promises
+// Combinators watch every promise they're handed, so ones that settle after
+// the combinator has don't go unobserved
+fn promises(list: &ContextualObject, h: Hydrator) -> Result<Vec<String>, Error> {
+    match &list.0 {
+        Object::Array(items) => {
+            let ids = items
+                .iter()
+                .map(|p| tasks::promise_id(p, h.clone()))
+                .collect::<Result<Vec<_>, _>>()?;
+            tasks::observe(&ids);
+            Ok(ids)
+        }
+        _ => Err(partial!(
+            "checking types",
+            format!("Expected an array of promises, found {}", list.0.typed()),
+            list.1,
+            h
+        )),
+    }
+}
+
+// Racing nothing would never settle
+fn some_promises(list: &ContextualObject, h: Hydrator) -> Result<Vec<String>, Error> {
+    let ids = promises(list, h.clone())?;
+    if ids.is_empty() {
+        return Err(partial!(
+            "checking types",
+            "Expected at least one promise",
+            list.1,
+            h
+        ));
+    }
+    Ok(ids)
+}
+
+//
+
+pub fn assert_args_len(
+    args: &[ContextualObject],
     len: usize,
     h: Hydrator,
 ) -> Result<(), Error> {
@@ -99,8 +249,8 @@ pub fn assert_args_len<'a>(
     Ok(())
 }
 
-pub fn assert_args_range<'a>(
-    args: &Vec<ContextualObject<'a>>,
+pub fn assert_args_range(
+    args: &[ContextualObject],
     range: RangeInclusive<usize>,
     h: Hydrator,
 ) -> Result<(), Error> {
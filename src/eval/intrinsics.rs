@@ -1,28 +1,48 @@
+use std::time::Duration;
+
 use itertools::Itertools;
 
 use crate::{
     errors::{Error, Hydrator},
     object::{ContextualObject, Object},
     scope::MutScope,
-    types::{Int, VariablySized},
+    types::{Int, Num, VariablySized},
 };
 
 use super::{
     builtins::{assert_args_len, assert_args_range},
-    tasks::Microtasker,
+    isolates::{self, Channel},
+    iterators::{lazy, step_result},
+    tasks,
 };
 
 pub fn list_instrinsics(typed: &str) -> &[&str] {
     match typed {
-        "string" => &["to_string", "len", "split"],
-        "array" => &["to_string", "len", "join", "map"],
-        "map" => &["to_string", "keys", "values", "entries"],
-        "promise" => &["to_string", "await"],
+        "string" => &[
+            "to_string", "len", "split", "iter", "filter", "take", "skip", "enumerate", "zip",
+        ],
+        "array" => &[
+            "to_string", "len", "join", "map", "iter", "filter", "take", "skip", "enumerate",
+            "zip",
+        ],
+        "map" => &[
+            "to_string", "keys", "values", "entries", "iter", "filter", "take", "skip",
+            "enumerate", "zip",
+        ],
+        "promise" => &["to_string", "await", "timeout", "cancel"],
+        "range" => &[
+            "to_string", "len", "iter", "filter", "take", "skip", "enumerate", "zip",
+        ],
+        "channel" => &["to_string", "send", "recv", "try_recv", "close", "iter"],
+        "iterator" => &[
+            "to_string", "next", "collect", "map", "iter", "filter", "take", "skip", "enumerate",
+            "zip",
+        ],
         _ => &["to_string"],
     }
 }
 
-pub fn get_intrinsic<'a>(ident: &str) -> Option<ContextualObject<'a>> {
+pub fn get_intrinsic(ident: &str) -> Option<ContextualObject> {
     Some(
         match ident {
             "to_string" => Object::Builtin(ident.to_string(), true, to_string),
@@ -34,6 +54,20 @@ pub fn get_intrinsic<'a>(ident: &str) -> Option<ContextualObject<'a>> {
             "entries" => Object::Builtin(ident.to_string(), true, entries),
             "map" => Object::Builtin(ident.to_string(), true, map),
             "await" => Object::Builtin(ident.to_string(), true, wait),
+            "timeout" => Object::Builtin(ident.to_string(), true, timeout),
+            "cancel" => Object::Builtin(ident.to_string(), true, cancel),
+            "send" => Object::Builtin(ident.to_string(), true, send),
+            "recv" => Object::Builtin(ident.to_string(), true, recv),
+            "try_recv" => Object::Builtin(ident.to_string(), true, try_recv),
+            "close" => Object::Builtin(ident.to_string(), true, close),
+            "iter" => Object::Builtin(ident.to_string(), true, iter),
+            "next" => Object::Builtin(ident.to_string(), true, next),
+            "collect" => Object::Builtin(ident.to_string(), true, collect),
+            "filter" => Object::Builtin(ident.to_string(), true, filter),
+            "take" => Object::Builtin(ident.to_string(), true, take),
+            "skip" => Object::Builtin(ident.to_string(), true, skip),
+            "enumerate" => Object::Builtin(ident.to_string(), true, enumerate),
+            "zip" => Object::Builtin(ident.to_string(), true, zip),
             _ => return None,
         }
         .anonymous(),
@@ -42,25 +76,29 @@ pub fn get_intrinsic<'a>(ident: &str) -> Option<ContextualObject<'a>> {
 
 //
 
-fn to_string<'a>(
-    a: Vec<ContextualObject<'a>>,
+fn to_string(
+    a: Vec<ContextualObject>,
     h: Hydrator,
-    _: MutScope<'a>,
-) -> Result<ContextualObject<'a>, Error> {
+    _: MutScope,
+) -> Result<ContextualObject, Error> {
     assert_args_len(&a, 1, h.clone())?;
     Ok(Object::String(a.first().unwrap().0.to_string()).anonymous())
 }
 
-fn len<'a>(
-    a: Vec<ContextualObject<'a>>,
+fn len(
+    a: Vec<ContextualObject>,
     h: Hydrator,
-    _: MutScope<'a>,
-) -> Result<ContextualObject<'a>, Error> {
+    _: MutScope,
+) -> Result<ContextualObject, Error> {
     assert_args_len(&a, 1, h.clone())?;
     let v = a.first().unwrap();
     Ok(Object::Integer(Int::fit(match &v.0.clone() {
         Object::Array(arr) => arr.len() as i128,
         Object::String(v) => v.len() as i128,
+        Object::Range(start, end, inclusive) => {
+            let len = end.to_max_value() - start.to_max_value() + *inclusive as i128;
+            len.max(0)
+        }
         _ => {
             return Err(partial!(
                 "checking types",
@@ -73,11 +111,11 @@ fn len<'a>(
     .anonymous())
 }
 
-fn split<'a>(
-    a: Vec<ContextualObject<'a>>,
+fn split(
+    a: Vec<ContextualObject>,
     h: Hydrator,
-    _: MutScope<'a>,
-) -> Result<ContextualObject<'a>, Error> {
+    _: MutScope,
+) -> Result<ContextualObject, Error> {
     assert_args_range(&a, 1..=2, h.clone())?;
 
     let (v, sep) = (
@@ -123,11 +161,11 @@ fn split<'a>(
     Ok(Object::Array(split).anonymous())
 }
 
-fn join<'a>(
-    a: Vec<ContextualObject<'a>>,
+fn join(
+    a: Vec<ContextualObject>,
     h: Hydrator,
-    _: MutScope<'a>,
-) -> Result<ContextualObject<'a>, Error> {
+    _: MutScope,
+) -> Result<ContextualObject, Error> {
     assert_args_len(&a, 2, h.clone())?;
     let (v, sep) = (a.first().unwrap(), a.last().unwrap());
     let sep = match &sep.0 {
@@ -163,11 +201,11 @@ fn join<'a>(
     .anonymous())
 }
 
-fn keys<'a>(
-    a: Vec<ContextualObject<'a>>,
+fn keys(
+    a: Vec<ContextualObject>,
     h: Hydrator,
-    _: MutScope<'a>,
-) -> Result<ContextualObject<'a>, Error> {
+    _: MutScope,
+) -> Result<ContextualObject, Error> {
     assert_args_len(&a, 1, h.clone())?;
     let v = a.first().unwrap();
     let v = match &v.0 {
@@ -185,11 +223,11 @@ fn keys<'a>(
     Ok(Object::Array(v.keys().map(|k| k.clone()).collect::<Vec<_>>()).anonymous())
 }
 
-fn values<'a>(
-    a: Vec<ContextualObject<'a>>,
+fn values(
+    a: Vec<ContextualObject>,
     h: Hydrator,
-    _: MutScope<'a>,
-) -> Result<ContextualObject<'a>, Error> {
+    _: MutScope,
+) -> Result<ContextualObject, Error> {
     assert_args_len(&a, 1, h.clone())?;
     let v = a.first().unwrap();
     let v = match &v.0 {
@@ -207,11 +245,11 @@ fn values<'a>(
     Ok(Object::Array(v.values().map(|v| v.clone()).collect::<Vec<_>>()).anonymous())
 }
 
-fn entries<'a>(
-    a: Vec<ContextualObject<'a>>,
+fn entries(
+    a: Vec<ContextualObject>,
     h: Hydrator,
-    _: MutScope<'a>,
-) -> Result<ContextualObject<'a>, Error> {
+    _: MutScope,
+) -> Result<ContextualObject, Error> {
     assert_args_len(&a, 1, h.clone())?;
     let v = a.first().unwrap();
     let v = match &v.0 {
@@ -234,14 +272,18 @@ fn entries<'a>(
     .anonymous())
 }
 
-fn map<'a>(
-    a: Vec<ContextualObject<'a>>,
+fn map(
+    a: Vec<ContextualObject>,
     h: Hydrator,
-    s: MutScope<'a>,
-) -> Result<ContextualObject<'a>, Error> {
+    s: MutScope,
+) -> Result<ContextualObject, Error> {
     assert_args_len(&a, 2, h.clone())?;
     let (v, f) = (a.first().unwrap(), a.last().unwrap());
+    let f = callback(f, "map", h.clone())?;
+
+    // Iterators stay lazy, arrays are mapped there and then
     let v = match &v.0 {
+        Object::Iterator(lazy) => return Ok(Object::Iterator(lazy.map(f, s, h)).anonymous()),
         Object::Array(v) => v,
         _ => {
             return Err(partial!(
@@ -253,32 +295,128 @@ fn map<'a>(
         }
     };
 
-    let f = match &f.0 {
-        Object::Lambda(..) => f,
-        Object::Builtin(..) => f,
-        _ => {
-            return Err(partial!(
-                "checking types",
-                format!("Can't map with type {}", f.0.typed()),
-                f.1,
-                h.clone()
-            ))
-        }
-    };
-
     v.into_iter()
         .map(|v| f.call(vec![v.clone()], s.clone(), h.clone()))
         .try_collect()
         .map(|v| Object::Array(v).anonymous())
 }
 
+pub fn callback(
+    f: &ContextualObject,
+    verb: &str,
+    h: Hydrator,
+) -> Result<ContextualObject, Error> {
+    match &f.0 {
+        Object::Lambda(..) | Object::Function(..) | Object::Builtin(..) => Ok(f.clone()),
+        _ => Err(partial!(
+            "checking types",
+            format!("Can't {verb} with type {}", f.0.typed()),
+            f.1,
+            h
+        )),
+    }
+}
+
+pub fn count(n: &ContextualObject, h: Hydrator) -> Result<usize, Error> {
+    match &n.0 {
+        Object::Integer(i) if i.to_max_value() >= 0 => Ok(i.to_max_value() as usize),
+        _ => Err(partial!(
+            "checking types",
+            format!("Expected a count, found {}", n.0),
+            "Counts are ints of zero or more",
+            n.1,
+            h
+        )),
+    }
+}
+
+//
+
+fn iter(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    Ok(Object::Iterator(lazy(a.first().unwrap(), s, h)?).anonymous())
+}
+
+fn next(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    Ok(step_result(lazy(a.first().unwrap(), s, h)?.next()?))
+}
+
+fn collect(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    Ok(Object::Array(lazy(a.first().unwrap(), s, h)?.collect()?).anonymous())
+}
+
+fn filter(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 2, h.clone())?;
+    let f = callback(a.last().unwrap(), "filter", h.clone())?;
+    let items = lazy(a.first().unwrap(), s.clone(), h.clone())?;
+    Ok(Object::Iterator(items.filter(f, s, h)).anonymous())
+}
+
+fn take(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 2, h.clone())?;
+    let n = count(a.last().unwrap(), h.clone())?;
+    Ok(Object::Iterator(lazy(a.first().unwrap(), s, h)?.take(n)).anonymous())
+}
+
+fn skip(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 2, h.clone())?;
+    let n = count(a.last().unwrap(), h.clone())?;
+    Ok(Object::Iterator(lazy(a.first().unwrap(), s, h)?.skip(n)).anonymous())
+}
+
+fn enumerate(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    Ok(Object::Iterator(lazy(a.first().unwrap(), s, h)?.enumerate()).anonymous())
+}
+
+fn zip(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 2, h.clone())?;
+    let items = lazy(a.first().unwrap(), s.clone(), h.clone())?;
+    let other = lazy(a.last().unwrap(), s, h)?;
+    Ok(Object::Iterator(items.zip(other)).anonymous())
+}
+
 //
 
-fn wait<'a>(
-    a: Vec<ContextualObject<'a>>,
+fn wait(
+    a: Vec<ContextualObject>,
     h: Hydrator,
-    _s: MutScope<'a>,
-) -> Result<ContextualObject<'a>, Error> {
+    _s: MutScope,
+) -> Result<ContextualObject, Error> {
     assert_args_len(&a, 1, h.clone())?;
     let id = match &a.first().unwrap().0 {
         Object::Promise(_, v) => v,
@@ -292,12 +430,104 @@ fn wait<'a>(
         }
     };
 
-    let ret: ContextualObject<'static> = Microtasker.write().unwrap().wait(id.clone());
+    tasks::wait(id)
+}
+
+fn timeout(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    _s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 2, h.clone())?;
+    let id = tasks::promise_id(&a[0], h.clone())?;
+    let ms = count(&a[1], h.clone())?;
+    let span = a[0].1;
+
+    Ok(tasks::queue_native(move || {
+        match tasks::wait_for(&id, Duration::from_millis(ms as u64)) {
+            Some(result) => result,
+            None => Err(partial!(
+                "awaiting",
+                format!("Timed out after {ms}ms"),
+                span,
+                h
+            )),
+        }
+    })
+    .anonymous())
+}
+
+fn cancel(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    _s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    let id = tasks::promise_id(&a[0], h.clone())?;
+    Ok(Object::Bool(tasks::cancel(&id, a[0].1, h)).anonymous())
+}
+
+//
 
-    unsafe {
-        Ok(std::mem::transmute::<
-            ContextualObject<'static>,
-            ContextualObject<'a>,
-        >(ret))
+fn channel(value: &ContextualObject, h: Hydrator) -> Result<Channel, Error> {
+    match &value.0 {
+        Object::Channel(channel) => Ok(channel.clone()),
+        _ => Err(partial!(
+            "checking types",
+            format!("Expected a channel, found {}", value.0.typed()),
+            value.1,
+            h
+        )),
     }
 }
+
+fn send(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    _s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 2, h.clone())?;
+    let channel = channel(&a[0], h.clone())?;
+    let value = isolates::copy(&a[1], a[1].1, h.clone())?;
+
+    if !channel.send(value) {
+        return Err(partial!(
+            "sending",
+            "Can't send on a closed channel",
+            a[1].1,
+            h
+        ));
+    }
+    Ok(Object::Null.anonymous())
+}
+
+// Closed channels hand back null once they've been emptied
+fn recv(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    _s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    let channel = channel(&a[0], h)?;
+    Ok(channel.recv().unwrap_or(Object::Null.anonymous()))
+}
+
+fn try_recv(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    _s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    let channel = channel(&a[0], h)?;
+    Ok(channel.try_recv().unwrap_or(Object::Null.anonymous()))
+}
+
+fn close(
+    a: Vec<ContextualObject>,
+    h: Hydrator,
+    _s: MutScope,
+) -> Result<ContextualObject, Error> {
+    assert_args_len(&a, 1, h.clone())?;
+    channel(&a[0], h)?.close();
+    Ok(Object::Null.anonymous())
+}
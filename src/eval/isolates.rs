@@ -0,0 +1,465 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use pest::Span;
+
+use crate::{
+    ast::{ContextualNode, ContextualPattern, Node, Pattern},
+    errors::{Error, Hydrator},
+    object::{ContextualObject, Object},
+    scope::{Captured, MutScope, Scope},
+};
+
+use super::tasks;
+
+/// Deep copies values out of one isolate's scope graph, so that another
+/// can have them without sharing anything but channels.
+pub struct Transfer {
+    // Scopes copied so far, by address, so cycles copy into cycles
+    scopes: BTreeMap<usize, MutScope>,
+    // Names that the functions copied so far look up in their scopes
+    wanted: BTreeSet<String>,
+    // Values in captured scopes that couldn't be copied, by name
+    left: Vec<(String, ContextualObject)>,
+    span: Span<'static>,
+    h: Hydrator,
+}
+
+impl Transfer {
+    /// Starts a transfer, blaming `span` for anything that can't be copied
+    /// and doesn't point anywhere itself.
+    pub fn new(span: Span<'static>, h: Hydrator) -> Self {
+        Transfer {
+            scopes: BTreeMap::new(),
+            wanted: BTreeSet::new(),
+            left: Vec::new(),
+            span,
+            h,
+        }
+    }
+
+    /// Copies `value`, along with the scopes any functions in it captured.
+    /// Builtins, promises and iterators are tied to the isolate they came
+    /// from, so they can't be copied.
+    pub fn value(&mut self, value: &ContextualObject) -> Result<ContextualObject, Error> {
+        let copied = match &value.0 {
+            Object::Array(items) => {
+                Object::Array(items.iter().map(|v| self.value(v)).collect::<Result<_, _>>()?)
+            }
+            Object::Map(entries) => Object::Map(
+                entries
+                    .iter()
+                    .map(|(k, v)| Ok((self.value(k)?, self.value(v)?)))
+                    .collect::<Result<_, Error>>()?,
+            ),
+            Object::Struct(name, fields) => Object::Struct(
+                name.clone(),
+                fields
+                    .iter()
+                    .map(|(f, v)| Ok((f.clone(), self.value(v)?)))
+                    .collect::<Result<_, Error>>()?,
+            ),
+            Object::Enum(name, variant, payload) => Object::Enum(
+                name.clone(),
+                variant.clone(),
+                match payload {
+                    Some(payload) => Some(Box::new(self.value(payload)?)),
+                    None => None,
+                },
+            ),
+            Object::Return(v) => Object::Return(Box::new(self.value(v)?)),
+            Object::Module(path, exports) => Object::Module(path.clone(), self.all(exports)?),
+            Object::Trait(name, required, defaults) => {
+                Object::Trait(name.clone(), required.clone(), self.all(defaults)?)
+            }
+
+            Object::Function(name, args, ret, body, captured) => {
+                self.wanted.extend(free_names(args, body));
+                Object::Function(
+                    name.clone(),
+                    args.clone(),
+                    ret.clone(),
+                    body.clone(),
+                    Captured(self.scope(&captured.0)),
+                )
+            }
+            Object::Lambda(args, ret, body, captured) => {
+                self.wanted.extend(free_names(args, body));
+                Object::Lambda(
+                    args.clone(),
+                    ret.clone(),
+                    body.clone(),
+                    Captured(self.scope(&captured.0)),
+                )
+            }
+
+            Object::Builtin(..) | Object::Promise(..) | Object::Iterator(..) => {
+                return Err(partial!(
+                    "transferring value",
+                    format!("Can't transfer {} to another isolate", value.0),
+                    "Builtins, promises and iterators belong to the isolate that made them",
+                    self.blame(value),
+                    self.h.clone()
+                ));
+            }
+
+            _ => value.0.clone(),
+        };
+
+        Ok(copied.provide_context(value.1))
+    }
+
+    /// Ends the transfer, failing if a function that was copied looks up a
+    /// name whose value had to be left behind.
+    pub fn finish(self) -> Result<(), Error> {
+        match self.left.iter().find(|(name, _)| self.wanted.contains(name)) {
+            Some((name, value)) => Err(partial!(
+                "transferring value",
+                format!("Can't transfer {name} to another isolate, it's {}", value.0),
+                "Builtins, promises and iterators belong to the isolate that made them",
+                self.span,
+                self.h.clone()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Values without a span of their own are blamed on whatever started
+    // the transfer
+    fn blame(&self, value: &ContextualObject) -> Span<'static> {
+        match value.1.as_str().is_empty() {
+            true => self.span,
+            false => value.1,
+        }
+    }
+
+    fn all(
+        &mut self,
+        values: &BTreeMap<String, ContextualObject>,
+    ) -> Result<BTreeMap<String, ContextualObject>, Error> {
+        values
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), self.value(v)?)))
+            .collect()
+    }
+
+    // Whatever in a captured scope can't be copied is left behind, and only
+    // matters if a function that was copied turns out to look it up
+    fn scope(&mut self, scope: &MutScope) -> MutScope {
+        let address = Arc::as_ptr(scope) as *const () as usize;
+        if let Some(copied) = self.scopes.get(&address) {
+            return copied.clone();
+        }
+
+        let copied = Scope::new("#pet.isolate");
+        self.scopes.insert(address, copied.clone());
+
+        let source = scope.read().unwrap();
+        let parent = source.parent().map(|p| self.scope(&p));
+        let filled = source.copy_with(parent, |name, v| match self.value(v) {
+            Ok(v) => Some(v),
+            Err(_) => {
+                self.left.push((name.to_string(), v.clone()));
+                None
+            }
+        });
+        *copied.write().unwrap() = filled;
+
+        copied
+    }
+}
+
+/// Copies a single value for another isolate.
+pub fn copy(value: &ContextualObject, span: Span<'static>, h: Hydrator) -> Result<ContextualObject, Error> {
+    let mut transfer = Transfer::new(span, h);
+    let copied = transfer.value(value)?;
+    transfer.finish()?;
+    Ok(copied)
+}
+
+/// Queues `f` to run with `args` as an isolate of its own, against copies
+/// of them, and hands back a promise of a copy of what it returns.
+pub fn spawn(
+    f: &ContextualObject,
+    args: &[ContextualObject],
+    span: Span<'static>,
+    h: Hydrator,
+) -> Result<Object, Error> {
+    let mut transfer = Transfer::new(span, h.clone());
+    let f = transfer.value(f)?;
+    let args = args
+        .iter()
+        .map(|a| transfer.value(a))
+        .collect::<Result<Vec<_>, Error>>()?;
+    transfer.finish()?;
+
+    Ok(tasks::queue_native(move || {
+        let result = tasks::isolated(|| {
+            let result = f.call(args, Scope::new("#pet.isolate"), h.clone())?;
+            tasks::drain()?;
+            Ok(result)
+        })?;
+        copy(&result, span, h)
+    }))
+}
+
+// The names that a function with `args` and `body` looks up in the scope it
+// captured. Names it declares anywhere in itself are taken to be its own.
+fn free_names(args: &[(String, String)], body: &[ContextualNode]) -> BTreeSet<String> {
+    let (mut used, mut declared) = (BTreeSet::new(), BTreeSet::new());
+    declared.extend(args.iter().map(|(name, _)| name.clone()));
+    all(body, &mut used, &mut declared);
+    used.difference(&declared).cloned().collect()
+}
+
+fn names(node: &ContextualNode, used: &mut BTreeSet<String>, declared: &mut BTreeSet<String>) {
+    match &node.0 {
+        Node::Ident(name) => {
+            used.insert(name.clone());
+        }
+        Node::FunctionCall { ident, args } => {
+            used.insert(ident.clone());
+            all(args, used, declared);
+        }
+        Node::Assignment {
+            ident, path, expr, ..
+        } => {
+            used.insert(ident.clone());
+            for key in path.iter().filter(|k| !matches!(k.0, Node::String(_))) {
+                names(key, used, declared);
+            }
+            names(expr, used, declared);
+        }
+        Node::Struct { ident, fields } => {
+            used.insert(ident.clone());
+            for (_, value) in fields {
+                names(value, used, declared);
+            }
+        }
+        Node::Impl { typed, methods, .. } => {
+            used.insert(typed.clone());
+            all(methods, used, declared);
+        }
+
+        Node::Delclaration { ident, expr, .. } => {
+            names(expr, used, declared);
+            declared.insert(ident.clone());
+        }
+        Node::FunctionDeclaration {
+            ident, args, body, ..
+        } => {
+            declared.insert(ident.clone());
+            declared.extend(args.iter().map(|(name, _)| name.clone()));
+            all(body, used, declared);
+        }
+        Node::Lambda { args, body, .. } => {
+            declared.extend(args.iter().map(|(name, _)| name.clone()));
+            all(body, used, declared);
+        }
+        Node::StructDeclaration { ident, .. } | Node::EnumDeclaration { ident, .. } => {
+            declared.insert(ident.clone());
+        }
+        Node::TraitDeclaration {
+            ident, defaults, ..
+        } => {
+            declared.insert(ident.clone());
+            all(defaults, used, declared);
+        }
+
+        // Methods and fields are looked up on what's indexed, not in scope
+        Node::Index(target, keys) => {
+            names(target, used, declared);
+            for key in keys {
+                match &key.0 {
+                    Node::Int(_) | Node::String(_) => {}
+                    Node::FunctionCall { args, .. } => all(args, used, declared),
+                    Node::Struct { fields, .. } => {
+                        for (_, value) in fields {
+                            names(value, used, declared);
+                        }
+                    }
+                    _ => names(key, used, declared),
+                }
+            }
+        }
+
+        Node::MondaicOp { expr, .. }
+        | Node::Return(expr)
+        | Node::Throw(expr)
+        | Node::Await(expr) => names(expr, used, declared),
+        Node::DyadicOp { lhs, rhs, .. } => {
+            names(lhs, used, declared);
+            names(rhs, used, declared);
+        }
+        Node::Range { start, end, .. } => {
+            names(start, used, declared);
+            names(end, used, declared);
+        }
+        Node::Yield(Some(expr)) | Node::Break { expr: Some(expr), .. } => names(expr, used, declared),
+        Node::Interpolated(nodes)
+        | Node::Terms(nodes)
+        | Node::Array(nodes)
+        | Node::Generator(nodes)
+        | Node::Loop { body: nodes, .. }
+        | Node::Async { body: nodes, .. } => all(nodes, used, declared),
+        Node::Map(entries) => {
+            for (key, value) in entries {
+                names(key, used, declared);
+                names(value, used, declared);
+            }
+        }
+        Node::Conditional { arms, else_arm } => {
+            for (condition, body) in arms {
+                names(condition, used, declared);
+                all(body, used, declared);
+            }
+            all(else_arm.as_deref().unwrap_or_default(), used, declared);
+        }
+        Node::LoopWhile {
+            condition, body, ..
+        } => {
+            names(condition, used, declared);
+            all(body, used, declared);
+        }
+        Node::LoopFor {
+            pattern,
+            iterable,
+            body,
+            ..
+        } => {
+            names(iterable, used, declared);
+            bindings(pattern, used, declared);
+            all(body, used, declared);
+        }
+        Node::Match { expr, arms } => {
+            names(expr, used, declared);
+            for (pattern, guard, body) in arms {
+                bindings(pattern, used, declared);
+                all(guard.as_slice(), used, declared);
+                all(body, used, declared);
+            }
+        }
+        Node::Try {
+            body,
+            catch,
+            finally,
+        } => {
+            all(body, used, declared);
+            if let Some((name, body)) = catch {
+                declared.extend(name.clone());
+                all(body, used, declared);
+            }
+            all(finally.as_deref().unwrap_or_default(), used, declared);
+        }
+
+        _ => {}
+    }
+}
+
+fn all(nodes: &[ContextualNode], used: &mut BTreeSet<String>, declared: &mut BTreeSet<String>) {
+    for node in nodes {
+        names(node, used, declared);
+    }
+}
+
+fn bindings(pattern: &ContextualPattern, used: &mut BTreeSet<String>, declared: &mut BTreeSet<String>) {
+    match &pattern.0 {
+        Pattern::Binding(name) => {
+            declared.insert(name.clone());
+        }
+        Pattern::Literal(node) => names(node, used, declared),
+        Pattern::Array(items) => items.iter().for_each(|p| bindings(p, used, declared)),
+        Pattern::Struct(_, fields) => fields.iter().for_each(|(_, p)| bindings(p, used, declared)),
+        Pattern::Variant(.., Some(payload)) => bindings(payload, used, declared),
+        Pattern::Variant(..) | Pattern::Wildcard => {}
+    }
+}
+
+//
+
+struct Messages {
+    queue: VecDeque<ContextualObject>,
+    closed: bool,
+}
+
+/// A queue of values that isolates can send each other copies through.
+/// Clones are the same channel.
+#[derive(Clone)]
+pub struct Channel(Arc<Mutex<Messages>>);
+
+impl Debug for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Channel({:p})", Arc::as_ptr(&self.0))
+    }
+}
+
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Channel {}
+
+impl PartialOrd for Channel {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Channel {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        Arc::as_ptr(&self.0).cmp(&Arc::as_ptr(&other.0))
+    }
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        let messages = Messages {
+            queue: VecDeque::new(),
+            closed: false,
+        };
+        Channel(Arc::new(Mutex::new(messages)))
+    }
+
+    /// Queues `value`, unless the channel has been closed.
+    pub fn send(&self, value: ContextualObject) -> bool {
+        let mut messages = self.0.lock().unwrap();
+        if messages.closed {
+            return false;
+        }
+
+        messages.queue.push_back(value);
+        drop(messages);
+        tasks::wake();
+        true
+    }
+
+    /// Blocks until there's a value, or until the channel is closed and
+    /// there won't be any more. The isolate's own queued tasks are run in
+    /// the meantime, in case they're what sends it.
+    pub fn recv(&self) -> Option<ContextualObject> {
+        tasks::block_on(|| {
+            let mut messages = self.0.lock().unwrap();
+            match messages.queue.pop_front() {
+                Some(value) => Some(Some(value)),
+                None if messages.closed => Some(None),
+                None => None,
+            }
+        })
+    }
+
+    pub fn try_recv(&self) -> Option<ContextualObject> {
+        self.0.lock().unwrap().queue.pop_front()
+    }
+
+    /// Stops any more values being sent. Ones already sent can still be
+    /// received.
+    pub fn close(&self) {
+        self.0.lock().unwrap().closed = true;
+        tasks::wake();
+    }
+}
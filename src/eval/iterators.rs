@@ -0,0 +1,386 @@
+use std::{
+    cell::RefCell,
+    fmt::Debug,
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use pest::Span;
+
+use crate::{
+    ast::ContextualNode,
+    errors::{Error, Hydrator},
+    object::{ContextualObject, Object},
+    scope::{MutScope, Scope},
+    types::{Int, Num, VariablySized},
+};
+
+use super::{step_block, unclaimed_signal};
+
+// Generators evaluate just as deeply as the main thread can
+const GENERATOR_STACK: usize = 64 * 1024 * 1024;
+
+type Step = Box<dyn FnMut() -> Result<Option<ContextualObject>, Error> + Send>;
+
+/// A sequence of values that are only worked out as they're asked for.
+/// Clones share their position, so stepping one steps them all.
+#[derive(Clone)]
+pub struct Lazy(Arc<Mutex<Step>>);
+
+impl Debug for Lazy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Lazy({:p})", Arc::as_ptr(&self.0))
+    }
+}
+
+impl PartialEq for Lazy {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Lazy {}
+
+impl PartialOrd for Lazy {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Lazy {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (Arc::as_ptr(&self.0) as *const () as usize)
+            .cmp(&(Arc::as_ptr(&other.0) as *const () as usize))
+    }
+}
+
+impl Lazy {
+    pub fn new(step: impl FnMut() -> Result<Option<ContextualObject>, Error> + Send + 'static) -> Self {
+        Lazy(Arc::new(Mutex::new(Box::new(step))))
+    }
+
+    /// Works out the next value, or `None` once there aren't any more.
+    pub fn next(&self) -> Result<Option<ContextualObject>, Error> {
+        (self.0.lock().unwrap())()
+    }
+
+    pub fn collect(&self) -> Result<Vec<ContextualObject>, Error> {
+        let mut items = Vec::new();
+        while let Some(item) = self.next()? {
+            items.push(item);
+        }
+        Ok(items)
+    }
+
+    pub fn map(&self, f: ContextualObject, scope: MutScope, h: Hydrator) -> Self {
+        let inner = self.clone();
+        Lazy::new(move || match inner.next()? {
+            Some(item) => f.call(vec![item], scope.clone(), h.clone()).map(Some),
+            None => Ok(None),
+        })
+    }
+
+    pub fn filter(&self, f: ContextualObject, scope: MutScope, h: Hydrator) -> Self {
+        let inner = self.clone();
+        Lazy::new(move || {
+            while let Some(item) = inner.next()? {
+                let keep = f.call(vec![item.clone()], scope.clone(), h.clone())?;
+                match keep.0 {
+                    Object::Bool(true) => return Ok(Some(item)),
+                    Object::Bool(false) => {}
+                    _ => {
+                        return Err(partial!(
+                            "filtering",
+                            format!("Expected bool, found {}", keep.0.typed()),
+                            "Filters decide whether to keep each item with true or false",
+                            keep.1,
+                            h.clone()
+                        ))
+                    }
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    pub fn take(&self, mut count: usize) -> Self {
+        let inner = self.clone();
+        Lazy::new(move || {
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+            inner.next()
+        })
+    }
+
+    pub fn skip(&self, mut count: usize) -> Self {
+        let inner = self.clone();
+        Lazy::new(move || {
+            while count > 0 {
+                count -= 1;
+                if inner.next()?.is_none() {
+                    return Ok(None);
+                }
+            }
+            inner.next()
+        })
+    }
+
+    pub fn enumerate(&self) -> Self {
+        let inner = self.clone();
+        let mut index = 0;
+        Lazy::new(move || {
+            let Some(item) = inner.next()? else {
+                return Ok(None);
+            };
+
+            let pair = vec![Object::Integer(Int::fit(index)).anonymous(), item];
+            index += 1;
+            Ok(Some(Object::Array(pair).anonymous()))
+        })
+    }
+
+    pub fn zip(&self, other: Lazy) -> Self {
+        let inner = self.clone();
+        Lazy::new(move || match (inner.next()?, other.next()?) {
+            (Some(a), Some(b)) => Ok(Some(Object::Array(vec![a, b]).anonymous())),
+            _ => Ok(None),
+        })
+    }
+}
+
+/// Gets a lazy view of anything that can be iterated over: arrays, the
+/// characters of a string, map entries as `[key, value]`, ranges,
+/// iterators, what's sent on a channel until it's closed, and values with a `next()` method that hands back
+/// `{value, done}`.
+pub fn lazy(
+    iterable: &ContextualObject,
+    scope: MutScope,
+    h: Hydrator,
+) -> Result<Lazy, Error> {
+    Ok(match &iterable.0 {
+        Object::Iterator(lazy) => lazy.clone(),
+        Object::Channel(channel) => {
+            let channel = channel.clone();
+            Lazy::new(move || Ok(channel.recv()))
+        }
+        Object::Array(v) => {
+            let mut items = v.clone().into_iter();
+            Lazy::new(move || Ok(items.next()))
+        }
+        Object::String(v) => {
+            let mut chars = v
+                .chars()
+                .map(|c| Object::String(c.to_string()).anonymous())
+                .collect::<Vec<_>>()
+                .into_iter();
+            Lazy::new(move || Ok(chars.next()))
+        }
+        Object::Map(v) => {
+            let mut entries = v
+                .iter()
+                .map(|(k, v)| Object::Array(vec![k.clone(), v.clone()]).anonymous())
+                .collect::<Vec<_>>()
+                .into_iter();
+            Lazy::new(move || Ok(entries.next()))
+        }
+        Object::Range(start, end, inclusive) => {
+            let (mut at, end) = (start.to_max_value(), end.to_max_value());
+            let end = if *inclusive { end + 1 } else { end };
+            Lazy::new(move || {
+                if at >= end {
+                    return Ok(None);
+                }
+                at += 1;
+                Ok(Some(Object::Integer(Int::fit(at - 1)).anonymous()))
+            })
+        }
+        _ if has_next(iterable, &scope) => {
+            let iterable = iterable.clone();
+            let mut done = false;
+            Lazy::new(move || {
+                if done {
+                    return Ok(None);
+                }
+
+                let container = Scope::new_from_object(iterable.clone(), scope.clone())?;
+                let next = container.read().unwrap().get("next").unwrap();
+                let result = next.call(vec![], container, h.clone())?;
+
+                let item = unpack(&result, h.clone())?;
+                done = item.is_none();
+                Ok(item)
+            })
+        }
+        _ => {
+            return Err(partial!(
+                "iterating",
+                format!("Can't iterate over type {}", iterable.0.typed()),
+                "Values with a next() method returning {value, done} can be iterated too",
+                iterable.1,
+                h.clone()
+            ))
+        }
+    })
+}
+
+fn has_next(value: &ContextualObject, scope: &MutScope) -> bool {
+    matches!(value.0, Object::Struct(..) | Object::Enum(..))
+        && scope
+            .read()
+            .unwrap()
+            .get_methods(&value.0.typed())
+            .contains_key("next")
+}
+
+/// What `next()` hands back: the value, and whether the sequence is done.
+pub fn step_result(item: Option<ContextualObject>) -> ContextualObject {
+    let done = item.is_none();
+    let mut result = std::collections::BTreeMap::new();
+    result.insert(
+        Object::String("value".to_string()).anonymous(),
+        item.unwrap_or(Object::Null.anonymous()),
+    );
+    result.insert(
+        Object::String("done".to_string()).anonymous(),
+        Object::Bool(done).anonymous(),
+    );
+    Object::Map(result).anonymous()
+}
+
+// Reads a `{value, done}` handed back by a user-defined `next()`
+fn unpack(
+    result: &ContextualObject,
+    h: Hydrator,
+) -> Result<Option<ContextualObject>, Error> {
+    let field = |name: &str| match &result.0 {
+        Object::Map(m) => m.get(&Object::String(name.to_string()).anonymous()).cloned(),
+        Object::Struct(_, fields) => fields.iter().find(|(f, _)| f == name).map(|(_, v)| v.clone()),
+        _ => None,
+    };
+
+    match field("done").map(|d| d.0) {
+        Some(Object::Bool(true)) => Ok(None),
+        Some(Object::Bool(false)) => Ok(Some(field("value").unwrap_or(Object::Null.anonymous()))),
+        _ => Err(partial!(
+            "iterating",
+            format!("Expected {{value, done}}, found {}", result.0),
+            "next() should hand back a map or struct with a bool `done`",
+            result.1,
+            h
+        )),
+    }
+}
+
+//
+
+enum Yielded {
+    Value(ContextualObject),
+    Done,
+    Failed(Error),
+}
+
+struct Active {
+    yielded: SyncSender<Yielded>,
+    resume: Receiver<()>,
+    abandoned: bool,
+}
+
+thread_local! {
+    // Set on the thread a generator's body runs on
+    static ACTIVE: RefCell<Option<Active>> = const { RefCell::new(None) };
+}
+
+/// Starts a generator over `body`, which runs on its own thread and only
+/// moves on to the next `yield` when its next value is asked for.
+pub fn generator(body: Vec<ContextualNode>, scope: MutScope, h: Hydrator) -> Lazy {
+    let (resume_tx, resume_rx) = sync_channel::<()>(0);
+    let (yielded_tx, yielded_rx) = sync_channel::<Yielded>(0);
+
+    thread::Builder::new()
+        .stack_size(GENERATOR_STACK)
+        .spawn(move || {
+            // Nothing runs until the first value is asked for
+            if resume_rx.recv().is_err() {
+                return;
+            }
+
+            ACTIVE.with(|active| {
+                *active.borrow_mut() = Some(Active {
+                    yielded: yielded_tx.clone(),
+                    resume: resume_rx,
+                    abandoned: false,
+                })
+            });
+
+            let outcome = match step_block(&body, scope.clone(), h.clone()) {
+                Err(e) => Yielded::Failed(e),
+                Ok(_) => match scope.write().unwrap().take_signal() {
+                    Some(signal) => Yielded::Failed(unclaimed_signal(signal, h)),
+                    None => Yielded::Done,
+                },
+            };
+
+            let _ = yielded_tx.send(outcome);
+        })
+        .unwrap();
+
+    let mut finished = false;
+    Lazy::new(move || {
+        if finished || resume_tx.send(()).is_err() {
+            return Ok(None);
+        }
+
+        match yielded_rx.recv() {
+            Ok(Yielded::Value(value)) => Ok(Some(value)),
+            Ok(Yielded::Failed(e)) => {
+                finished = true;
+                Err(e)
+            }
+            Ok(Yielded::Done) | Err(_) => {
+                finished = true;
+                Ok(None)
+            }
+        }
+    })
+}
+
+/// Hands `value` to whoever asked the running generator for its next
+/// value, then waits until the one after is wanted.
+pub fn yield_value(
+    value: ContextualObject,
+    span: Span<'static>,
+    h: Hydrator,
+) -> Result<(), Error> {
+    ACTIVE.with(|active| {
+        let mut active = active.borrow_mut();
+        let Some(active) = active.as_mut() else {
+            return Err(partial!(
+                "yielding",
+                "`yield` outside of a generator",
+                "Only functions and lambdas can yield",
+                span,
+                h
+            ));
+        };
+
+        let resumed = active.yielded.send(Yielded::Value(value)).is_ok() && active.resume.recv().is_ok();
+        if !resumed {
+            // Nothing can ask for more values, so the body is unwound
+            active.abandoned = true;
+            return Err(partial!("yielding", "The generator was dropped", span, h));
+        }
+
+        Ok(())
+    })
+}
+
+/// Whether this thread is unwinding a generator nobody can resume, which
+/// `catch` mustn't get in the way of.
+pub fn abandoned() -> bool {
+    ACTIVE.with(|active| active.borrow().as_ref().is_some_and(|a| a.abandoned))
+}
@@ -1,29 +1,67 @@
+use std::collections::BTreeMap;
+
 use itertools::Itertools;
+use pest::Span;
 
 use crate::{
-    ast::{op::Dyadic, ContextualNode, Node, Program},
+    ast::{
+        op::{Dyadic, Mondaic},
+        ContextualNode, Node, Program, VariantShape,
+    },
     errors::{Error, Hydrator},
     helpers::extend,
     object::{ContextualObject, Object},
-    scope::{MutScope, Scope},
+    scope::{Captured, MutScope, Scope, Signal},
     types::{Float, Int, Num, VariablySized},
 };
 
 pub mod builtins;
 pub mod intrinsics;
+pub mod isolates;
+pub mod iterators;
+pub mod modules;
+pub mod patterns;
 pub mod repl;
 pub mod tasks;
 
-pub fn eval<'a>(
-    prog: Program<'a>,
-    scope: MutScope<'a>,
+pub fn eval(
+    prog: Program,
+    scope: MutScope,
+    h: Hydrator,
+) -> miette::Result<ContextualObject> {
+    let result = step_block(&prog.tree, scope.clone(), h.clone())?;
+    if let Some(signal) = scope.write().unwrap().take_signal() {
+        return Err(unclaimed_signal(signal, h).into());
+    }
+    Ok(match result.0 {
+        Object::Return(expr) => *expr,
+        _ => result,
+    })
+}
+
+/// Runs a list of statements in `scope`, yielding the value of the last one.
+/// Declarations are bound before anything else runs, so they can be used from
+/// anywhere in the block. A `return` stops the block early, and is handed back
+/// as-is for the enclosing function to unwrap.
+pub fn step_block(
+    body: &[ContextualNode],
+    scope: MutScope,
     h: Hydrator,
-) -> miette::Result<ContextualObject<'a>> {
+) -> Result<ContextualObject, Error> {
+    for phase in 0..3 {
+        for node in body.iter().filter(|n| hoisting_phase(n) == Some(phase)) {
+            step(node, scope.clone(), h.clone())?;
+        }
+    }
+
     let mut result: ContextualObject = Object::Null.anonymous();
-    for node in prog.tree {
-        result = step(&node, scope.clone(), h.clone())?;
-        if let Object::Return(expr) = &result.0 {
-            result = *expr.clone();
+    let runnable = |n: &&ContextualNode| hoisting_phase(n).is_none() && !matches!(n.0, Node::Doc(_));
+    for node in body.iter().filter(runnable) {
+        result = step(node, scope.clone(), h.clone())?;
+        if let Object::Return(..) = &result.0 {
+            break;
+        }
+        if scope.read().unwrap().signal().is_some() {
             break;
         }
     }
@@ -31,11 +69,32 @@ pub fn eval<'a>(
     Ok(result)
 }
 
-pub fn step<'a>(
-    node: &ContextualNode<'a>,
-    scope: MutScope<'a>,
+// Runs `body` in a scope of its own, releasing the scope once it's left
+fn step_in(body: &[ContextualNode], scope: MutScope, h: Hydrator) -> Result<ContextualObject, Error> {
+    let result = step_block(body, scope.clone(), h);
+    Scope::release(scope);
+    result
+}
+
+// Imports come first, so declarations can use what they bring in. Impls go
+// after everything else, as they refer to the types and traits
+fn hoisting_phase(node: &ContextualNode) -> Option<usize> {
+    match node.0 {
+        Node::Import { .. } => Some(0),
+        Node::FunctionDeclaration { .. }
+        | Node::StructDeclaration { .. }
+        | Node::EnumDeclaration { .. }
+        | Node::TraitDeclaration { .. } => Some(1),
+        Node::Impl { .. } => Some(2),
+        _ => None,
+    }
+}
+
+pub fn step(
+    node: &ContextualNode,
+    scope: MutScope,
     h: Hydrator,
-) -> Result<ContextualObject<'a>, Error> {
+) -> Result<ContextualObject, Error> {
     // println!("Step :: {:?}", node.0);
     match node.0.clone() {
         // Literals
@@ -43,6 +102,13 @@ pub fn step<'a>(
         Node::Int(v) => Ok(Object::Integer(Int::fit(v)).provide_context(node.1.clone())),
         Node::Bool(v) => Ok(Object::Bool(v).provide_context(node.1.clone())),
         Node::String(v) => Ok(Object::String(v).provide_context(node.1.clone())),
+        Node::Interpolated(parts) => {
+            let mut text = String::new();
+            for part in parts {
+                text.push_str(&step(&part, scope.clone(), h.clone())?.0.to_string());
+            }
+            Ok(Object::String(text).provide_context(node.1))
+        }
         Node::Array(v) => {
             let v = v
                 .into_iter()
@@ -50,23 +116,86 @@ pub fn step<'a>(
                 .try_collect()?;
             Ok(Object::Array(v).provide_context(node.1.clone()))
         }
+        Node::Map(entries) => {
+            let mut map = BTreeMap::new();
+            for (key, value) in entries {
+                // Keys are kept without a span, so equal keys are found wherever they came from
+                let key = step(&key, scope.clone(), h.clone())?.0.anonymous();
+                map.insert(key, step(&value, scope.clone(), h.clone())?);
+            }
+            Ok(Object::Map(map).provide_context(node.1))
+        }
         Node::Null => Ok(Object::Null.provide_context(node.1.clone())),
 
         // Operations
+        Node::MondaicOp { verb, expr } => step_monad(verb, *expr, node.1, scope, h.clone()),
         Node::DyadicOp { verb, lhs, rhs } => step_dyad(verb, *lhs, *rhs, scope, h.clone()),
 
         // Variables
-        Node::Delclaration { ident, expr, .. } => {
+        Node::Delclaration {
+            ident,
+            typed,
+            expr,
+            public,
+            ..
+        } => {
             let value = step(&*expr, scope.clone(), h.clone())?;
-            scope
-                .write()
-                .unwrap()
-                .set(&ident, value.clone(), node.1, h.clone())?;
+            if let Some(typed) = &typed {
+                value.expect_type(
+                    typed,
+                    format!("{ident} is declared as {typed}"),
+                    expr.1,
+                    &scope.read().unwrap(),
+                    h.clone(),
+                )?;
+            }
+            declare(&scope, &ident, value.clone(), public, node.1, h)?;
+            if let Some(typed) = typed {
+                scope.write().unwrap().declare_type(&ident, &typed);
+            }
             Ok(value)
         }
 
-        Node::Assignment { ident, expr } => {
+        Node::Assignment {
+            ident,
+            path,
+            compound,
+            expr,
+        } => {
             let value = step(&*expr, scope.clone(), h.clone())?;
+            if !path.is_empty() {
+                let keys = path
+                    .iter()
+                    .map(|key| match &key.0 {
+                        Node::String(field) => Ok(Object::String(field.clone()).provide_context(key.1)),
+                        _ => step(key, scope.clone(), h.clone()),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let target = step(
+                    &Node::Ident(ident.clone()).provide_context(node.1),
+                    scope.clone(),
+                    h.clone(),
+                )?;
+                let mut assigned = value.clone();
+                let updated = assign_path(
+                    target,
+                    &keys,
+                    |current| {
+                        if let Some(verb) = compound {
+                            assigned = apply_dyad(verb, current, value, h.clone())?;
+                        }
+                        Ok(assigned.clone())
+                    },
+                    h.clone(),
+                )?;
+                scope
+                    .write()
+                    .unwrap()
+                    .assign(&ident, updated, node.1, h.clone())?;
+                return Ok(assigned);
+            }
+
             scope
                 .write()
                 .unwrap()
@@ -103,13 +232,17 @@ pub fn step<'a>(
 
             let v = v.clone();
             match v.0 {
-                Object::Builtin(..) | Object::Lambda(..) => {
+                Object::Builtin(..)
+                | Object::Lambda(..)
+                | Object::Function(..)
+                | Object::VariantConstructor(..) => {
                     let args = args
                         .into_iter()
                         .map(|a| step(&a, scope.clone(), h.clone()))
                         .try_collect()?;
 
-                    v.call(args, scope.clone(), h)
+                    v.call(args, scope.clone(), h.clone())
+                        .map_err(|e| e.called_from(&ident, node.1, h))
                 }
 
                 _ => Err(partial!(
@@ -124,7 +257,7 @@ pub fn step<'a>(
         // Indexing
         Node::Index(left, right) => {
             let left = step(&*left, scope.clone(), h.clone())?;
-            let mut container: MutScope<'a> = Scope::new_from_object(left, scope.clone())?;
+            let mut container: MutScope = Scope::new_from_object(left, scope.clone())?;
 
             for (index, item) in right.clone().into_iter().enumerate() {
                 let obj = match item.0 {
@@ -133,11 +266,40 @@ pub fn step<'a>(
                         .unwrap()
                         .get(&v.to_string())
                         .unwrap_or(Object::Null.anonymous()),
-                    Node::String(v) | Node::Ident(v) => container
+                    Node::String(v) => container
                         .read()
                         .unwrap()
                         .get(&v)
                         .unwrap_or(Object::Null.anonymous()),
+                    Node::Struct { ident, fields } => {
+                        let constructor = container.read().unwrap().get(&ident);
+                        match constructor.map(|c| c.0) {
+                            Some(Object::VariantConstructor(
+                                typed,
+                                variant,
+                                VariantShape::Struct(declared),
+                            )) => {
+                                let values =
+                                    instantiate(&variant, &declared, fields, item.1, scope.clone(), h.clone())?;
+                                Object::Enum(
+                                    typed,
+                                    variant.clone(),
+                                    Some(Box::new(
+                                        Object::Struct(variant, values).provide_context(item.1),
+                                    )),
+                                )
+                                .provide_context(item.1)
+                            }
+                            _ => {
+                                return Err(partial!(
+                                    "evaluating index",
+                                    format!("Unknown struct variant: {}", ident),
+                                    item.1,
+                                    h.clone()
+                                ))
+                            }
+                        }
+                    }
                     Node::FunctionCall { ident, args } => {
                         let object = container
                             .read()
@@ -148,22 +310,30 @@ pub fn step<'a>(
                                 format!("Unknown element: {}", ident),
                                 item.1.clone(),
                                 h.clone()
-                            ))?
-                            .0;
-
-                        match object {
-                            Object::Builtin(_, slf, f) => {
-                                let prev = container.read().unwrap().name.clone();
-                                container.clone().write().unwrap().name =
-                                    "object_fncall".to_string();
+                            ))?;
 
-                                let mut args: Vec<ContextualObject<'a>> = args
-                                    .into_iter()
-                                    .map(|a| step(&a, container.clone(), h.clone()))
-                                    .try_collect()?;
+                        if !matches!(
+                            object.0,
+                            Object::Builtin(..)
+                                | Object::Function(..)
+                                | Object::Lambda(..)
+                                | Object::VariantConstructor(..)
+                        ) {
+                            return Err(partial!(
+                                "evaluating index",
+                                format!("Can't index with this type"),
+                                item.1.clone(),
+                                h.clone()
+                            ));
+                        }
 
-                                container.clone().write().unwrap().name = prev;
+                        let mut args: Vec<ContextualObject> = args
+                            .into_iter()
+                            .map(|a| step(&a, scope.clone(), h.clone()))
+                            .try_collect()?;
 
+                        let result = match object.0 {
+                            Object::Builtin(_, slf, f) => {
                                 if slf {
                                     let slf =
                                         container.read().unwrap().get_self().ok_or(partial!(
@@ -176,25 +346,20 @@ pub fn step<'a>(
                                     args.insert(0, slf.clone());
                                 }
 
-                                f(args, h.clone(), scope.clone())?
+                                f(args, h.clone(), scope.clone())
                             }
-                            _ => {
-                                return Err(partial!(
-                                    "evaluating index",
-                                    format!("Can't index with this type"),
-                                    item.1.clone(),
-                                    h.clone()
-                                ))
-                            }
-                        }
+                            _ => object.call(args, container.clone(), h.clone()),
+                        };
+
+                        result.map_err(|e| e.called_from(&ident, item.1, h.clone()))?
                     }
                     _ => {
-                        return Err(partial!(
-                            "evaluating index",
-                            "Can't index with this type".to_string(),
-                            item.1.clone(),
-                            h.clone()
-                        ))
+                        let key = step(&item, scope.clone(), h.clone())?;
+                        container
+                            .read()
+                            .unwrap()
+                            .get(&key.0.to_string())
+                            .unwrap_or(Object::Null.anonymous())
                     }
                 };
 
@@ -212,71 +377,834 @@ pub fn step<'a>(
             args,
             return_type,
             body,
-        } => Ok(Object::Lambda(args, return_type, body).provide_context(node.1.clone())),
+        } => Ok(
+            Object::Lambda(args, return_type, body, Captured(scope.clone())).provide_context(node.1),
+        ),
+
+        Node::FunctionDeclaration {
+            ident,
+            args,
+            return_type,
+            body,
+            public,
+            ..
+        } => {
+            let function = Object::Function(
+                ident.clone(),
+                args,
+                return_type,
+                body,
+                Captured(scope.clone()),
+            )
+            .provide_context(node.1);
+            declare(&scope, &ident, function.clone(), public, node.1, h)?;
+            Ok(function)
+        }
+
+        // Structs
+        Node::StructDeclaration {
+            ident,
+            fields,
+            public,
+            ..
+        } => {
+            let typed = Object::StructType(ident.clone(), fields).provide_context(node.1);
+            declare(&scope, &ident, typed.clone(), public, node.1, h)?;
+            Ok(typed)
+        }
+
+        Node::Struct { ident, fields } => {
+            let typed = scope.read().unwrap().get(&ident).ok_or(partial!(
+                "instantiating struct",
+                format!("Unknown struct: {}", ident),
+                node.1,
+                h.clone()
+            ))?;
+
+            let Object::StructType(_, declared) = typed.0 else {
+                return Err(partial!(
+                    "instantiating struct",
+                    format!("{} is not a struct, it's a {}", ident, typed.0.typed()),
+                    node.1,
+                    h.clone()
+                ));
+            };
+
+            let values = instantiate(&ident, &declared, fields, node.1, scope, h)?;
+            Ok(Object::Struct(ident, values).provide_context(node.1))
+        }
+
+        // Enums
+        Node::EnumDeclaration {
+            public,
+            ident,
+            variants,
+        } => {
+            let typed = Object::EnumType(ident.clone(), variants).provide_context(node.1);
+            declare(&scope, &ident, typed.clone(), public, node.1, h)?;
+            Ok(typed)
+        }
+
+        Node::Match { expr, arms } => {
+            let value = step(&expr, scope.clone(), h.clone())?;
+
+            for (pattern, guard, body) in arms {
+                let mut bindings = Vec::new();
+                if !patterns::matches(&pattern, &value, &mut bindings, scope.clone(), h.clone())? {
+                    continue;
+                }
+
+                let arm_scope = Scope::new_child(scope.clone(), "#pet.match");
+                for (name, bound) in bindings {
+                    arm_scope.write().unwrap().force_set(&name, bound);
+                }
+
+                if let Some(guard) = guard {
+                    if step(&guard, arm_scope.clone(), h.clone())?.0 != Object::Bool(true) {
+                        continue;
+                    }
+                }
+
+                return step_in(&body, arm_scope, h);
+            }
+
+            Err(partial!(
+                "evaluating match",
+                format!("No arm matched {}", value.0),
+                "You can add a `_` arm to catch everything else",
+                expr.1,
+                h.clone()
+            ))
+        }
+
+        Node::TraitDeclaration {
+            public,
+            ident,
+            required,
+            defaults,
+        } => {
+            let defaults = defaults
+                .into_iter()
+                .map(|d| declare_method(d, scope.clone()))
+                .collect();
+
+            let typed = Object::Trait(ident.clone(), required, defaults).provide_context(node.1);
+            declare(&scope, &ident, typed.clone(), public, node.1, h)?;
+            Ok(typed)
+        }
+
+        Node::Import { path, alias, names } => {
+            let module = modules::load(&path, node.1, h.clone())?;
+            let module = module.read().unwrap();
+            scope.write().unwrap().adopt_methods(&module);
+
+            let exports = module.exports();
+            if let Some(alias) = alias {
+                let module = Object::Module(path, exports).provide_context(node.1);
+                scope.write().unwrap().set(&alias, module, node.1, h.clone())?;
+                return Ok(Object::Null.anonymous());
+            }
+
+            for name in names {
+                let Node::Ident(ident) = &name.0 else {
+                    unreachable!()
+                };
+
+                let Some(value) = exports.get(ident) else {
+                    return Err(partial!(
+                        "importing module",
+                        format!("{path} doesn't export {ident}"),
+                        match module.get(ident) {
+                            Some(_) => format!("{ident} is private, mark it `pub` to export it"),
+                            None => format!("Try one of: {}", exports.keys().join(", ")),
+                        },
+                        name.1,
+                        h.clone()
+                    ));
+                };
+
+                scope
+                    .write()
+                    .unwrap()
+                    .set(ident, value.clone(), name.1, h.clone())?;
+            }
+
+            Ok(Object::Null.anonymous())
+        }
+
+        Node::Impl {
+            typed,
+            trait_name,
+            methods,
+        } => {
+            let methods = methods
+                .into_iter()
+                .map(|m| declare_method(m, scope.clone()))
+                .collect::<Vec<_>>();
+
+            let inherent = trait_name.is_none();
+            if let Some(trait_name) = trait_name {
+                let found = scope.read().unwrap().get(&trait_name).ok_or(partial!(
+                    "implementing trait",
+                    format!("Unknown trait: {}", trait_name),
+                    node.1,
+                    h.clone()
+                ))?;
+
+                let Object::Trait(_, required, defaults) = found.0 else {
+                    return Err(partial!(
+                        "implementing trait",
+                        format!("{} is not a trait, it's a {}", trait_name, found.0.typed()),
+                        node.1,
+                        h.clone()
+                    ));
+                };
+
+                if let Some((name, method)) = methods
+                    .iter()
+                    .find(|(m, _)| !required.contains(m) && !defaults.contains_key(m))
+                {
+                    return Err(partial!(
+                        "implementing trait",
+                        format!("{} is not a method of {}", name, trait_name),
+                        method.1,
+                        h.clone()
+                    ));
+                }
+
+                let missing = required
+                    .iter()
+                    .filter(|r| !methods.iter().any(|(m, _)| m == *r))
+                    .map(|r| r.as_str())
+                    .collect::<Vec<_>>();
+
+                if !missing.is_empty() {
+                    return Err(partial!(
+                        "implementing trait",
+                        format!(
+                            "{} is missing methods required by {}: {}",
+                            typed,
+                            trait_name,
+                            missing.join(", ")
+                        ),
+                        node.1,
+                        h.clone()
+                    ));
+                }
+
+                for (name, default) in defaults {
+                    scope
+                        .write()
+                        .unwrap()
+                        .define_trait_method(&typed, &name, default);
+                }
+            }
+
+            for (name, method) in methods {
+                let mut scope = scope.write().unwrap();
+                match inherent {
+                    true => scope.define_method(&typed, &name, method),
+                    false => scope.define_trait_method(&typed, &name, method),
+                }
+            }
+
+            Ok(Object::Null.provide_context(node.1))
+        }
 
         Node::Conditional { arms, else_arm } => {
             for (cond, body) in arms {
                 let cond = step(&cond, scope.clone(), h.clone())?;
                 if let Object::Bool(true) = cond.0 {
-                    for node in body {
-                        let result = step(&node, scope.clone(), h.clone())?;
-                        if let Object::Return(expr) = &result.0 {
-                            return Ok(*expr.clone());
-                        }
+                    let result = step_block(&body, scope.clone(), h.clone())?;
+                    if let Object::Return(..) = &result.0 {
+                        return Ok(result);
                     }
                     return Ok(Object::Null.anonymous());
                 }
             }
 
             if let Some(else_arm) = else_arm {
-                for node in else_arm {
-                    let result = step(&node, scope.clone(), h.clone())?;
-                    if let Object::Return(expr) = &result.0 {
-                        return Ok(*expr.clone());
-                    }
+                let result = step_block(&else_arm, scope.clone(), h.clone())?;
+                if let Object::Return(..) = &result.0 {
+                    return Ok(result);
                 }
             }
 
             Ok(Object::Null.anonymous())
         }
 
-        Node::LoopWhile { condition, body } => {
-            let mut loops = 0;
-
+        Node::LoopWhile {
+            label,
+            condition,
+            body,
+        } => {
             loop {
-                loops += 1;
                 let cond = step(&*condition, scope.clone(), h.clone())?;
                 if let Object::Bool(true) = cond.0 {
-                    for node in &body {
-                        let result = step(&node, scope.clone(), h.clone())?;
-                        if let Object::Return(expr) = &result.0 {
-                            return Ok(*expr.clone());
-                        }
+                    let iter_scope = Scope::new_child(scope.clone(), "#pet.while");
+                    let result = step_in(&body, iter_scope, h.clone())?;
+                    if let Object::Return(..) = &result.0 {
+                        return Ok(result);
+                    }
+                    if let Flow::Exit(value) = claim_signal(&label, &scope) {
+                        return Ok(value);
                     }
                 } else {
                     break;
                 }
             }
 
-            Ok(Object::Integer(Int::fit(loops)).anonymous())
+            Ok(Object::Null.anonymous())
+        }
+
+        Node::Loop { label, body } => loop {
+            let iter_scope = Scope::new_child(scope.clone(), "#pet.loop");
+            let result = step_in(&body, iter_scope, h.clone())?;
+            if let Object::Return(..) = &result.0 {
+                return Ok(result);
+            }
+            if let Flow::Exit(value) = claim_signal(&label, &scope) {
+                return Ok(value);
+            }
+        },
+
+        Node::Break { label, expr } => {
+            let value = match expr {
+                Some(expr) => step(&expr, scope.clone(), h.clone())?,
+                None => Object::Null.anonymous(),
+            };
+
+            scope
+                .write()
+                .unwrap()
+                .raise(Signal::Break(label, value, node.1));
+            Ok(Object::Null.anonymous())
         }
 
+        Node::Continue { label } => {
+            scope.write().unwrap().raise(Signal::Continue(label, node.1));
+            Ok(Object::Null.anonymous())
+        }
+
+        Node::LoopFor {
+            label,
+            pattern,
+            iterable,
+            body,
+        } => {
+            let iterable = step(&iterable, scope.clone(), h.clone())?;
+            let items = iterators::lazy(&iterable, scope.clone(), h.clone())?;
+
+            while let Some(item) = items.next()? {
+                let mut bindings = Vec::new();
+                if !patterns::matches(&pattern, &item, &mut bindings, scope.clone(), h.clone())? {
+                    return Err(partial!(
+                        "iterating",
+                        format!("Pattern didn't match {}", item.0),
+                        pattern.1,
+                        h.clone()
+                    ));
+                }
+
+                let iter_scope = Scope::new_child(scope.clone(), "#pet.for");
+                for (name, bound) in bindings {
+                    iter_scope.write().unwrap().force_set(&name, bound);
+                }
+
+                let result = step_in(&body, iter_scope, h.clone())?;
+                if let Object::Return(..) = &result.0 {
+                    return Ok(result);
+                }
+                if let Flow::Exit(value) = claim_signal(&label, &scope) {
+                    return Ok(value);
+                }
+            }
+
+            Ok(Object::Null.anonymous())
+        }
+
+        Node::Yield(expr) => {
+            let value = match expr {
+                Some(expr) => step(&expr, scope, h.clone())?,
+                None => Object::Null.provide_context(node.1),
+            };
+            iterators::yield_value(value, node.1, h)?;
+            Ok(Object::Null.provide_context(node.1))
+        }
+
+        Node::Generator(body) => {
+            let generator = Object::Iterator(iterators::generator(body, scope, h));
+            Ok(Object::Return(Box::new(generator.provide_context(node.1))).provide_context(node.1))
+        }
+
+        Node::Async { typed, body } => {
+            let task_scope = Scope::new_child(scope, "#pet.call");
+            Ok(tasks::queue_microtask(body, task_scope, &typed, h).provide_context(node.1))
+        }
+
+        Node::Await(expr) => {
+            let promise = tasks::awaited(true, || step(&expr, scope, h.clone()))?;
+            match &promise.0 {
+                Object::Promise(_, id) => tasks::wait(id),
+                _ => Err(partial!(
+                    "awaiting",
+                    format!("Can't await type {}", promise.0.typed()),
+                    "Only promises, from async functions and blocks, can be awaited",
+                    promise.1,
+                    h
+                )),
+            }
+        }
+
+        Node::Throw(expr) => {
+            let value = step(&expr, scope, h.clone())?;
+            Err(thrown(value, node.1, h))
+        }
+
+        Node::Try {
+            body,
+            catch,
+            finally,
+        } => {
+            let mut result = step_block(&body, scope.clone(), h.clone());
+
+            // A generator nobody can resume has to unwind all the way out
+            let catchable = !iterators::abandoned();
+            if let (Err(_), Some((binding, handler)), true) = (&result, catch, catchable) {
+                let error = result.unwrap_err();
+                let catch_scope = Scope::new_child(scope.clone(), "#pet.catch");
+                if let Some(binding) = binding {
+                    catch_scope
+                        .write()
+                        .unwrap()
+                        .force_set(&binding, caught(error, node.1));
+                }
+                result = step_in(&handler, catch_scope, h.clone());
+            }
+
+            if let Some(finally) = finally {
+                // A pending break or continue waits for the cleanup to run first
+                let signal = scope.write().unwrap().take_signal();
+                step_block(&finally, scope.clone(), h.clone())?;
+                if let Some(signal) = signal {
+                    let mut scope = scope.write().unwrap();
+                    if scope.signal().is_none() {
+                        scope.raise(signal);
+                    }
+                }
+            }
+
+            let result = result?;
+            if let Object::Return(..) = &result.0 {
+                return Ok(result);
+            }
+            Ok(Object::Null.anonymous())
+        }
+
+        Node::Range {
+            start,
+            end,
+            inclusive,
+        } => {
+            let bound = |n: &ContextualNode| -> Result<Int, Error> {
+                match step(n, scope.clone(), h.clone())? {
+                    ContextualObject(Object::Integer(v), _) => Ok(v),
+                    v => Err(partial!(
+                        "building range",
+                        format!("Range bounds must be int, got {}", v.0.typed()),
+                        n.1,
+                        h.clone()
+                    )),
+                }
+            };
+
+            Ok(Object::Range(bound(&start)?, bound(&end)?, inclusive).provide_context(node.1))
+        }
+
+        Node::Doc(_) => Ok(Object::Null.anonymous()),
+
         _ => todo!(),
     }
 }
 
-fn step_dyad<'a>(
+// Rebuilds `target` with the value at the end of `keys` replaced by what
+// `update` makes of it, for assignments like `a.b[0] = value`
+fn assign_path(
+    target: ContextualObject,
+    keys: &[ContextualObject],
+    update: impl FnOnce(ContextualObject) -> Result<ContextualObject, Error>,
+    h: Hydrator,
+) -> Result<ContextualObject, Error> {
+    let Some((key, rest)) = keys.split_first() else {
+        return update(target);
+    };
+
+    let updated = match (target.0, &key.0) {
+        (Object::Array(mut items), Object::Integer(i)) => {
+            let len = items.len();
+            let slot = usize::try_from(i.to_max_value())
+                .ok()
+                .and_then(|i| items.get_mut(i))
+                .ok_or(partial!(
+                    "assigning index",
+                    format!("Index {} is out of range", key.0),
+                    format!("The array has {len} items"),
+                    key.1,
+                    h.clone()
+                ))?;
+            *slot = assign_path(slot.clone(), rest, update, h)?;
+            Object::Array(items)
+        }
+        (Object::Map(mut map), _) => {
+            let existing = map
+                .keys()
+                .find(|k| k.0.to_string() == key.0.to_string())
+                .cloned();
+            let inner = match existing.as_ref().and_then(|k| map.remove(k)) {
+                Some(inner) => inner,
+                None if rest.is_empty() => Object::Null.anonymous(),
+                None => {
+                    return Err(partial!(
+                        "assigning index",
+                        format!("No key {} in map", key.0),
+                        key.1,
+                        h.clone()
+                    ))
+                }
+            };
+            let key = existing.unwrap_or(key.0.clone().anonymous());
+            map.insert(key, assign_path(inner, rest, update, h)?);
+            Object::Map(map)
+        }
+        (Object::Struct(name, mut fields), _) => {
+            let available = fields.iter().map(|(f, _)| f.as_str()).join(", ");
+            let field = key.0.to_string();
+            let slot = fields.iter_mut().find(|(f, _)| *f == field).ok_or(partial!(
+                "assigning field",
+                format!("No field {field} on {name}"),
+                format!("Available fields are: {available}"),
+                key.1,
+                h.clone()
+            ))?;
+            slot.1 = assign_path(slot.1.clone(), rest, update, h)?;
+            Object::Struct(name, fields)
+        }
+        (Object::Array(_), _) => {
+            return Err(partial!(
+                "assigning index",
+                format!("Arrays are indexed by int, not {}", key.0.typed()),
+                key.1,
+                h.clone()
+            ))
+        }
+        (target, _) => {
+            return Err(partial!(
+                "assigning index",
+                format!("Can't assign into type {}", target.typed()),
+                key.1,
+                h.clone()
+            ))
+        }
+    };
+
+    Ok(updated.provide_context(target.1))
+}
+
+// What a loop should do once its body has run
+enum Flow {
+    Next,
+    Exit(ContextualObject),
+}
+
+// Loops take the signals meant for them, and step aside for the ones meant for
+// a loop further out
+fn claim_signal(label: &Option<String>, scope: &MutScope) -> Flow {
+    let mut scope = scope.write().unwrap();
+    match scope.signal() {
+        None => Flow::Next,
+        Some(signal) if !signal.targets(label) => Flow::Exit(Object::Null.anonymous()),
+        Some(_) => match scope.take_signal() {
+            Some(Signal::Break(_, value, _)) => Flow::Exit(value),
+            _ => Flow::Next,
+        },
+    }
+}
+
+/// Reports a `break` or `continue` that made it out of a function (or the
+/// program) without any loop claiming it.
+pub fn unclaimed_signal(signal: Signal, h: Hydrator) -> Error {
+    let keyword = match signal {
+        Signal::Break(..) => "break",
+        Signal::Continue(..) => "continue",
+    };
+
+    match signal.label() {
+        Some(label) => partial!(
+            "leaving loop",
+            format!("No loop labeled '{label} around this `{keyword}`"),
+            "Labels go right before a loop, as in `'outer: loop {}`",
+            signal.span(),
+            h.clone()
+        ),
+        None => partial!(
+            "leaving loop",
+            format!("`{keyword}` outside of a loop"),
+            signal.span(),
+            h.clone()
+        ),
+    }
+}
+
+/// Turns a thrown value into an error that unwinds until something catches
+/// it. Errors that were caught and thrown again keep their message.
+fn thrown(value: ContextualObject, span: Span<'static>, h: Hydrator) -> Error {
+    let field = |name: &str| match &value.0 {
+        Object::Struct(typed, fields) if typed == "Error" => fields
+            .iter()
+            .find(|(f, v)| f == name && v.0 != Object::Null)
+            .map(|(_, v)| v.0.to_string()),
+        _ => None,
+    };
+
+    let message = field("message").unwrap_or_else(|| format!("Uncaught exception: {}", value.0));
+    let mut error = match field("hint") {
+        Some(hint) => partial!("running", message, hint, span, h),
+        None => partial!("running", message, span, h),
+    };
+
+    error.thrown = Some(Box::new(value));
+    error
+}
+
+/// What a `catch` is handed: the value that was thrown, or an `Error`
+/// describing whatever went wrong inside petal.
+fn caught(error: Error, span: Span<'static>) -> ContextualObject {
+    let error = *error.0;
+    if let Some(value) = error.thrown {
+        return *value;
+    }
+
+    let (line, column) = error.position;
+    let fields = [
+        ("message", Object::String(error.error)),
+        ("hint", error.hint.map_or(Object::Null, Object::String)),
+        ("file", Object::String(error.source_path)),
+        ("line", Object::Integer(Int::fit(line as i128))),
+        ("column", Object::Integer(Int::fit(column as i128))),
+    ];
+
+    Object::Struct(
+        "Error".to_string(),
+        fields
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.provide_context(span)))
+            .collect(),
+    )
+    .provide_context(span)
+}
+
+// Evaluates the fields given for a struct (or struct-like variant), in declaration order
+fn instantiate(
+    ident: &str,
+    declared: &[(String, String)],
+    fields: Vec<(ContextualNode, ContextualNode)>,
+    span: Span<'static>,
+    scope: MutScope,
+    h: Hydrator,
+) -> Result<Vec<(String, ContextualObject)>, Error> {
+    let mut values = Vec::new();
+    for (name, expr) in fields {
+        let Node::Ident(name_str) = &name.0 else {
+            unreachable!()
+        };
+
+        if !declared.iter().any(|(f, _)| f == name_str) {
+            return Err(partial!(
+                "instantiating struct",
+                format!("{} has no field {}", ident, name_str),
+                format!(
+                    "Available fields are: {}",
+                    declared.iter().map(|(f, _)| f.as_str()).join(", ")
+                ),
+                name.1,
+                h.clone()
+            ));
+        }
+
+        if values.iter().any(|(f, _)| f == name_str) {
+            return Err(partial!(
+                "instantiating struct",
+                format!("Field {} is set more than once", name_str),
+                name.1,
+                h.clone()
+            ));
+        }
+
+        values.push((name_str.clone(), step(&expr, scope.clone(), h.clone())?));
+    }
+
+    let missing = declared
+        .iter()
+        .filter(|(f, _)| !values.iter().any(|(v, _)| v == f))
+        .map(|(f, _)| f.as_str())
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        return Err(partial!(
+            "instantiating struct",
+            format!("Missing fields for {}: {}", ident, missing.join(", ")),
+            span,
+            h.clone()
+        ));
+    }
+
+    values.sort_by_key(|(f, _)| declared.iter().position(|(d, _)| d == f));
+    Ok(values)
+}
+
+// Binds a declaration, making it visible to importers if it's `pub`
+fn declare(
+    scope: &MutScope,
+    ident: &str,
+    value: ContextualObject,
+    public: bool,
+    span: Span<'static>,
+    h: Hydrator,
+) -> Result<(), Error> {
+    let mut scope = scope.write().unwrap();
+    scope.set(ident, value, span, h)?;
+    if public {
+        scope.export(ident);
+    }
+    Ok(())
+}
+
+// Turns a function declaration into a method, without binding it to a name
+fn declare_method(
+    node: ContextualNode,
+    scope: MutScope,
+) -> (String, ContextualObject) {
+    let Node::FunctionDeclaration {
+        ident,
+        args,
+        return_type,
+        body,
+        ..
+    } = node.0
+    else {
+        unreachable!()
+    };
+
+    let function = Object::Function(ident.clone(), args, return_type, body, Captured(scope))
+        .provide_context(node.1);
+    (ident, function)
+}
+
+fn step_monad(
+    verb: Mondaic,
+    operand: ContextualNode,
+    span: Span<'static>,
+    scope: MutScope,
+    h: Hydrator,
+) -> Result<ContextualObject, Error> {
+    let operand = step(&operand, scope, h.clone())?;
+
+    Ok(match (verb, &operand.0) {
+        (Mondaic::Negate, Object::Bool(v)) => Object::Bool(!v),
+        (Mondaic::Negative, Object::Integer(v)) => Object::Integer(Int::fit(-v.to_max_value())),
+        (Mondaic::Negative, Object::Float(v)) => Object::Float(Float::fit(-v.to_max_value())),
+        (Mondaic::BitNot, Object::Integer(v)) => Object::Integer(Int::fit(!v.to_max_value())),
+        _ => {
+            return Err(partial!(
+                "evaluating mondaic",
+                format!("can't use verb {} on type {}", verb.to_symbol(), operand.0.typed()),
+                match verb {
+                    Mondaic::Negate => "'!' only works on bools",
+                    Mondaic::Negative => "'-' only works on ints and floats",
+                    Mondaic::BitNot => "'~' only works on ints",
+                },
+                operand.1,
+                h.clone()
+            ))
+        }
+    }
+    .provide_context(span))
+}
+
+fn step_dyad(
     verb: Dyadic,
-    left: ContextualNode<'a>,
-    right: ContextualNode<'a>,
-    scope: MutScope<'a>,
+    left: ContextualNode,
+    right: ContextualNode,
+    scope: MutScope,
     h: Hydrator,
-) -> Result<ContextualObject<'a>, Error> {
+) -> Result<ContextualObject, Error> {
     let left = step(&left, scope.clone(), h.clone())?;
+
+    // These only look at their right side when the left one doesn't settle it
+    match (verb, &left.0) {
+        (Dyadic::Coalesce, Object::Null) => return step(&right, scope, h),
+        (Dyadic::Coalesce, _) => return Ok(left),
+        (Dyadic::And | Dyadic::Or, Object::Bool(settled)) if *settled == (verb == Dyadic::Or) => {
+            return Ok(left)
+        }
+        (Dyadic::And | Dyadic::Or, Object::Bool(_)) => {
+            let right = step(&right, scope, h.clone())?;
+            if !matches!(right.0, Object::Bool(_)) {
+                return Err(partial!(
+                    "evaluating dyadic",
+                    format!("can't use verb {} on type {}", verb.to_symbol(), right.0.typed()),
+                    right.1,
+                    h.clone()
+                ));
+            }
+            return Ok(right);
+        }
+        (Dyadic::And | Dyadic::Or, _) => {
+            return Err(partial!(
+                "evaluating dyadic",
+                format!("can't use verb {} on type {}", verb.to_symbol(), left.0.typed()),
+                left.1,
+                h.clone()
+            ))
+        }
+        _ => (),
+    }
+
     let right = step(&right, scope.clone(), h.clone())?;
+    apply_dyad(verb, left, right, h)
+}
+
+/// Applies a dyad to two values that have already been evaluated.
+pub fn apply_dyad(
+    verb: Dyadic,
+    left: ContextualObject,
+    right: ContextualObject,
+    h: Hydrator,
+) -> Result<ContextualObject, Error> {
     let span = extend(&[left.1.clone(), right.1.clone()]);
 
-    let (left, right) = Object::coerce(left, right, h.clone())?;
+    if verb == Dyadic::NotEqual {
+        let equal = apply_dyad(Dyadic::Equality, left, right, h)?;
+        return Ok(Object::Bool(equal.0 == Object::Bool(false)).provide_context(span));
+    }
+
+    // Values that can't be coerced are still comparable, they just aren't equal
+    let (left, right) = match Object::coerce(left.clone(), right.clone(), h.clone()) {
+        Ok(coerced) => coerced,
+        Err(_) if verb == Dyadic::Equality => (left, right),
+        Err(e) => return Err(e),
+    };
+
+    if matches!(verb, Dyadic::Divide | Dyadic::IntDivide | Dyadic::Modulo)
+        && matches!(&right.0, Object::Integer(b) if b.to_max_value() == 0)
+    {
+        return Err(partial!(
+            "evaluating dyadic",
+            format!("can't use verb {} with a zero divisor", verb.to_symbol()),
+            right.1,
+            h.clone()
+        ));
+    }
 
     Ok(match (left.0.clone(), right.0.clone()) {
         (Object::Float(a), Object::Float(b)) => match verb {
@@ -284,6 +1212,10 @@ fn step_dyad<'a>(
             Dyadic::Subtract => Object::Float(Float::fit(a.to_max_value() - b.to_max_value())),
             Dyadic::Multiply => Object::Float(Float::fit(a.to_max_value() * b.to_max_value())),
             Dyadic::Divide => Object::Float(Float::fit(a.to_max_value() / b.to_max_value())),
+            Dyadic::IntDivide => {
+                Object::Integer(Int::fit((a.to_max_value() / b.to_max_value()).floor() as i128))
+            }
+            Dyadic::Modulo => Object::Float(Float::fit(a.to_max_value() % b.to_max_value())),
             Dyadic::Pow => Object::Float(Float::fit(a.to_max_value().powf(b.to_max_value()))),
             Dyadic::Equality => Object::Bool(a.to_max_value() == b.to_max_value()),
             Dyadic::GreaterThan => Object::Bool(a.to_max_value() > b.to_max_value()),
@@ -304,7 +1236,58 @@ fn step_dyad<'a>(
             Dyadic::Subtract => Object::Integer(Int::fit(a.to_max_value() - b.to_max_value())),
             Dyadic::Multiply => Object::Integer(Int::fit(a.to_max_value() * b.to_max_value())),
             Dyadic::Divide => Object::Integer(Int::fit(a.to_max_value() / b.to_max_value())),
-            Dyadic::Pow => Object::Integer(Int::fit(a.to_max_value().pow(b.to_max_value() as u32))),
+            Dyadic::IntDivide => {
+                let (a, b) = (a.to_max_value(), b.to_max_value());
+                let floored = a / b - ((a % b != 0) && ((a < 0) != (b < 0))) as i128;
+                Object::Integer(Int::fit(floored))
+            }
+            Dyadic::Modulo => Object::Integer(Int::fit(a.to_max_value() % b.to_max_value())),
+            Dyadic::BitAnd => Object::Integer(Int::fit(a.to_max_value() & b.to_max_value())),
+            Dyadic::BitOr => Object::Integer(Int::fit(a.to_max_value() | b.to_max_value())),
+            Dyadic::BitXor => Object::Integer(Int::fit(a.to_max_value() ^ b.to_max_value())),
+            Dyadic::ShiftLeft | Dyadic::ShiftRight => {
+                let shifted = u32::try_from(b.to_max_value()).ok().and_then(|by| match verb {
+                    Dyadic::ShiftLeft => a.to_max_value().checked_shl(by),
+                    _ => a.to_max_value().checked_shr(by),
+                });
+
+                match shifted {
+                    Some(v) => Object::Integer(Int::fit(v)),
+                    None => {
+                        return Err(partial!(
+                            "evaluating dyadic",
+                            format!("can't shift by {}", b.to_max_value()),
+                            "Shifts must be between 0 and 127",
+                            right.1,
+                            h.clone()
+                        ))
+                    }
+                }
+            }
+            Dyadic::Pow => {
+                let (a, b) = (a.to_max_value(), b.to_max_value());
+                if b < 0 {
+                    return Err(partial!(
+                        "evaluating dyadic",
+                        format!("can't raise an integer to the power of {b}"),
+                        "Integer exponents can't be negative, but float ones can, like 2.0 ** -1",
+                        right.1,
+                        h.clone()
+                    ));
+                }
+
+                match u32::try_from(b).ok().and_then(|b| a.checked_pow(b)) {
+                    Some(v) => Object::Integer(Int::fit(v)),
+                    None => {
+                        return Err(partial!(
+                            "evaluating dyadic",
+                            format!("{a} ** {b} is too large for an integer"),
+                            span,
+                            h.clone()
+                        ))
+                    }
+                }
+            }
             Dyadic::Equality => Object::Bool(a.to_max_value() == b.to_max_value()),
             Dyadic::GreaterThan => Object::Bool(a.to_max_value() > b.to_max_value()),
             Dyadic::LessThan => Object::Bool(a.to_max_value() < b.to_max_value()),
@@ -323,6 +1306,9 @@ fn step_dyad<'a>(
             Dyadic::Equality => Object::Bool(a == b),
             Dyadic::Or => Object::Bool(a || b),
             Dyadic::And => Object::Bool(a && b),
+            Dyadic::BitAnd => Object::Bool(a & b),
+            Dyadic::BitOr => Object::Bool(a | b),
+            Dyadic::BitXor => Object::Bool(a ^ b),
             _ => {
                 return Err(partial!(
                     "evaluating dyadic",
@@ -345,6 +1331,7 @@ fn step_dyad<'a>(
                 ))
             }
         },
+        (a, b) if verb == Dyadic::Equality => Object::Bool(a == b),
         _ => {
             return Err(partial!(
                 "evaluating dyadic",
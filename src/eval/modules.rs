@@ -0,0 +1,171 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use itertools::Itertools;
+use owo_colors::OwoColorize;
+use pest::Span;
+
+use crate::{
+    ast::Program,
+    errors::{Error, Hydrator},
+    scope::{MutScope, Scope},
+};
+
+use super::tasks;
+
+/// Every module that has been run, by canonical path, along with the chain of
+/// modules that are still running their imports.
+#[allow(non_upper_case_globals)]
+pub static Modules: once_cell::sync::Lazy<Arc<RwLock<ModuleCache>>> =
+    once_cell::sync::Lazy::new(|| Arc::new(RwLock::new(ModuleCache::default())));
+
+#[derive(Default)]
+pub struct ModuleCache {
+    loaded: BTreeMap<PathBuf, MutScope>,
+    loading: Vec<PathBuf>,
+}
+
+/// Runs a file given on the command line, as the root of its own imports.
+pub fn run_entry(path: &str) -> miette::Result<()> {
+    let content = read_entry(path)?;
+    let canonical = Path::new(path).canonicalize().map_err(|e| unreadable(path, e))?;
+    run(canonical, path.to_string(), content)?;
+
+    // Whatever the file left running gets to finish before it's done
+    tasks::drain()?;
+    Ok(())
+}
+
+/// Type checks a file given to `pet check`, printing everything that's
+/// wrong with it and returning how many problems there were.
+pub fn check_entry(path: &str) -> miette::Result<usize> {
+    let content = read_entry(path)?;
+    let errors = Program::make(content, Some(path.to_string()))?.check();
+    let count = errors.len();
+
+    for error in errors {
+        eprintln!("{:?}", miette::Report::new(error));
+    }
+
+    Ok(count)
+}
+
+/// Prints the doc comments of a file given to `pet doc`, under the
+/// signatures of the items they document.
+pub fn doc_entry(path: &str) -> miette::Result<()> {
+    let content = read_entry(path)?;
+    for (signature, doc) in Program::make(content, Some(path.to_string()))?.docs() {
+        println!("{}", signature.bold());
+        for line in doc.lines() {
+            match line.is_empty() {
+                true => println!(),
+                false => println!("    {line}"),
+            }
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+fn read_entry(path: &str) -> Result<String, Error> {
+    std::fs::read_to_string(path).map_err(|e| unreadable(path, e))
+}
+
+// Files named on the command line have no source pointing at them, so the
+// error points at the path itself
+fn unreadable(path: &str, e: std::io::Error) -> Error {
+    let h: Hydrator = (path.to_string(), Arc::new(path.to_string()));
+    let source: &'static str = Box::leak(path.into());
+    partial!(
+        "reading file",
+        format!("Couldn't read {path}: {e}"),
+        Span::new(source, 0, source.len()).unwrap(),
+        h
+    )
+}
+
+/// Finds the module `path` refers to from the file being run, running it
+/// the first time it's imported.
+pub fn load(path: &str, span: Span<'static>, h: Hydrator) -> Result<MutScope, Error> {
+    let base = match h.0.starts_with("#pet") {
+        true => env::current_dir().unwrap_or_default(),
+        false => Path::new(&h.0).parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+
+    let resolved = base.join(path);
+    let canonical = resolved.canonicalize().map_err(|_| {
+        partial!(
+            "importing module",
+            format!("Couldn't find module {path}"),
+            format!("Looked for {}", resolved.display()),
+            span,
+            h.clone()
+        )
+    })?;
+
+    let cached = {
+        let modules = Modules.read().unwrap();
+        if let Some(start) = modules.loading.iter().position(|p| *p == canonical) {
+            let chain = modules.loading[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|p| display(p))
+                .join(" -> ");
+
+            return Err(partial!(
+                "importing module",
+                format!("Circular import of {path}"),
+                format!("The import chain is {chain}"),
+                span,
+                h.clone()
+            ));
+        }
+
+        modules.loaded.get(&canonical).cloned()
+    };
+
+    let scope = match cached {
+        Some(scope) => scope,
+        None => {
+            let content = std::fs::read_to_string(&canonical).map_err(|e| {
+                partial!(
+                    "importing module",
+                    format!("Couldn't read module {path}: {e}"),
+                    span,
+                    h.clone()
+                )
+            })?;
+            run(canonical.clone(), display(&canonical), content)?
+        }
+    };
+
+    Ok(scope)
+}
+
+fn run(canonical: PathBuf, name: String, content: String) -> Result<MutScope, Error> {
+    Modules.write().unwrap().loading.push(canonical.clone());
+
+    let scope = Scope::new("#pet.global");
+    let result = Program::make(content, Some(name)).and_then(|p| p.eval(Some(scope.clone())));
+
+    let mut modules = Modules.write().unwrap();
+    modules.loading.pop();
+    result.map_err(|e| e.downcast::<Error>().unwrap())?;
+
+    modules.loaded.insert(canonical, scope.clone());
+    Ok(scope)
+}
+
+// Paths are shown relative to where petal was run from, when they can be
+fn display(path: &Path) -> String {
+    let cwd = env::current_dir().unwrap_or_default();
+    path.strip_prefix(&cwd)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
@@ -0,0 +1,119 @@
+use crate::{
+    ast::{ContextualPattern, Pattern},
+    errors::{Error, Hydrator},
+    object::{ContextualObject, Object},
+    scope::MutScope,
+};
+
+use super::step;
+
+/// Checks `value` against `pattern`, collecting any names the pattern binds.
+/// Bindings are only meaningful when this returns true.
+pub fn matches(
+    pattern: &ContextualPattern,
+    value: &ContextualObject,
+    bindings: &mut Vec<(String, ContextualObject)>,
+    scope: MutScope,
+    h: Hydrator,
+) -> Result<bool, Error> {
+    Ok(match (&pattern.0, &value.0) {
+        (Pattern::Wildcard, _) => true,
+        (Pattern::Binding(name), _) => {
+            bindings.push((name.clone(), value.clone()));
+            true
+        }
+        (Pattern::Literal(literal), _) => step(literal, scope, h)?.0 == value.0,
+
+        (Pattern::Array(patterns), Object::Array(values)) => {
+            if patterns.len() != values.len() {
+                return Ok(false);
+            }
+
+            for (pattern, value) in patterns.iter().zip(values.iter()) {
+                if !matches(pattern, value, bindings, scope.clone(), h.clone())? {
+                    return Ok(false);
+                }
+            }
+
+            true
+        }
+
+        (Pattern::Struct(typed, patterns), Object::Struct(name, fields)) => {
+            if typed != name {
+                return Ok(false);
+            }
+
+            for (field, pattern) in patterns {
+                let Some((_, value)) = fields.iter().find(|(f, _)| f == field) else {
+                    return Err(partial!(
+                        "matching pattern",
+                        format!("{} has no field {}", typed, field),
+                        pattern.1,
+                        h.clone()
+                    ));
+                };
+
+                if !matches(pattern, value, bindings, scope.clone(), h.clone())? {
+                    return Ok(false);
+                }
+            }
+
+            true
+        }
+
+        (Pattern::Variant(typed, variant, payload), _) => {
+            check_variant(typed, variant, pattern, scope.clone(), h.clone())?;
+
+            let Object::Enum(name, found, value) = &value.0 else {
+                return Ok(false);
+            };
+
+            if name != typed || found != variant {
+                return Ok(false);
+            }
+
+            match (payload, value) {
+                (Some(pattern), Some(value)) => matches(pattern, value, bindings, scope, h)?,
+                (Some(_), None) => false,
+                (None, _) => true,
+            }
+        }
+
+        _ => false,
+    })
+}
+
+// Catches typos in variant patterns, which would otherwise just never match
+fn check_variant(
+    typed: &str,
+    variant: &str,
+    pattern: &ContextualPattern,
+    scope: MutScope,
+    h: Hydrator,
+) -> Result<(), Error> {
+    match scope.read().unwrap().get(typed).map(|t| t.0) {
+        Some(Object::EnumType(_, variants)) => match variants.iter().any(|(v, _)| v == variant) {
+            true => Ok(()),
+            false => Err(partial!(
+                "matching pattern",
+                format!("{} has no variant {}", typed, variant),
+                format!(
+                    "Try one of: {}",
+                    variants
+                        .iter()
+                        .map(|(v, _)| v.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                pattern.1,
+                h.clone()
+            )),
+        },
+        _ => Err(partial!(
+            "matching pattern",
+            format!("Unknown enum: {}", typed),
+            pattern.1,
+            h.clone()
+        )),
+    }
+}
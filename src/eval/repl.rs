@@ -12,7 +12,7 @@ use crate::{
 use super::builtins;
 
 #[derive(Completer, Helper, Validator, Highlighter)]
-struct PetalHinter<'a>(MutScope<'a>);
+struct PetalHinter(MutScope);
 
 #[derive(Debug, PartialEq, Eq)]
 struct CommandHint(pub String);
@@ -33,7 +33,7 @@ impl CommandHint {
     }
 }
 
-impl<'a> Hinter for PetalHinter<'a> {
+impl Hinter for PetalHinter {
     type Hint = CommandHint;
 
     fn hint(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> Option<Self::Hint> {
@@ -45,7 +45,11 @@ impl<'a> Hinter for PetalHinter<'a> {
 
         let mut hints = self.0.clone().read().unwrap().list_vars();
         hints.extend(
-            vec!["let", "fn", "if", "else", "exit"]
+            vec![
+                "let", "fn", "struct", "enum", "impl", "trait", "match", "for", "while", "loop",
+                "break", "continue", "if", "else", "import", "from", "pub", "try", "catch",
+                "finally", "throw", "yield", "async", "await", "exit",
+            ]
                 .into_iter()
                 .map(|a| a.to_string())
                 .collect::<Vec<_>>(),
@@ -72,7 +76,7 @@ impl<'a> Hinter for PetalHinter<'a> {
     }
 }
 
-pub fn repl<'a>() {
+pub fn repl() {
     print!("\x1B[2J\x1B[1;1H");
     println!("# {} repl", "petal".bright_magenta());
     println!("type 'exit' to exit\n");
@@ -99,7 +103,7 @@ pub fn repl<'a>() {
                     }
                 );
 
-                match Program::<'a>::make(program.to_string(), None) {
+                match Program::make(program.to_string(), None) {
                     Ok(p) => match p.eval(Some(scope)) {
                         Ok(v) => println!("{}\n", v.0.pretty_print()),
                         Err(e) => println!("\n{:?}\n", e),
@@ -112,7 +116,7 @@ pub fn repl<'a>() {
 }
 
 fn should_append_semicolon(i: &str) -> bool {
-    !vec!["struct", "trait", "fn", "pub", "local", "impl", "return"]
+    !vec!["struct", "enum", "trait", "fn", "pub", "local", "impl", "return"]
         .iter()
         .any(|t| i.starts_with(t))
 }
@@ -1,141 +1,579 @@
 use std::{
-    collections::BTreeMap,
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     env,
-    sync::{Arc, RwLock},
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
-use tokio::sync::{
-    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-    Mutex,
+use pest::Span;
+use tokio::{
+    runtime::{self, Runtime},
+    sync::mpsc::{unbounded_channel, UnboundedSender},
 };
 
 use crate::{
-    ast::{ContextualNode, Program},
-    errors::Hydrator,
+    ast::ContextualNode,
+    errors::{Error, Hydrator},
     object::{ContextualObject, Object},
-    scope::MutScope,
+    scope::{MutScope, Scope},
 };
 
+use super::{step_block, unclaimed_signal};
+
+// Tasks evaluate just as deeply as the main thread can
+const TASK_STACK: usize = 64 * 1024 * 1024;
+
 #[allow(non_upper_case_globals)]
-pub static Microtasker: once_cell::sync::Lazy<Arc<RwLock<MicrotaskScheduler<'static>>>> =
-    once_cell::sync::Lazy::new(|| Arc::new(RwLock::new(MicrotaskScheduler::new())));
+pub static Microtasker: once_cell::sync::Lazy<Arc<Mutex<MicrotaskScheduler>>> =
+    once_cell::sync::Lazy::new(|| Arc::new(Mutex::new(MicrotaskScheduler::new())));
+
+// Woken whenever a task is queued or settles, for anything waiting on one
+static SETTLED: Condvar = Condvar::new();
+
+// Timers are kept on a tokio runtime of their own, so that they fire
+// whatever the threads running petal are up to
+static TIMERS: once_cell::sync::Lazy<Runtime> = once_cell::sync::Lazy::new(|| {
+    runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_time()
+        .build()
+        .unwrap()
+});
+
+// Isolates are numbered as they're spawned, the program itself being 0
+static ISOLATES: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    // Whether the expression being evaluated is what an `await` waits on
+    static AWAITED: Cell<bool> = const { Cell::new(false) };
+    // The isolate whose code this thread is running
+    static ISOLATE: Cell<usize> = const { Cell::new(0) };
+}
+
+type Native = Box<dyn FnOnce() -> Result<ContextualObject, Error> + Send>;
+
+pub enum Work {
+    // A block of petal, promising a value of type `typed`
+    Block {
+        typed: String,
+        body: Vec<ContextualNode>,
+        scope: MutScope,
+        hydrator: Hydrator,
+    },
+    // Work done by the runtime itself, such as waiting on other promises
+    Native(Native),
+}
+
+pub struct Microtask {
+    pub id: String,
+    pub work: Work,
+}
 
 pub enum MicrotaskInstruction {
-    Task(Vec<ContextualNode<'static>>, MutScope<'static>, Hydrator),
-    Result(ContextualObject<'static>),
+    Task(Microtask),
 }
 
+/// A worker thread, which runs the tasks it's handed one at a time.
 #[derive(Clone)]
 pub struct MicrotaskChannel {
     pub tx: Arc<UnboundedSender<MicrotaskInstruction>>,
-    pub rx: Arc<Mutex<UnboundedReceiver<MicrotaskInstruction>>>,
-    pub id: Arc<RwLock<String>>,
 }
 
 impl MicrotaskChannel {
-    pub fn new() -> Self {
-        let (tx, rx) = unbounded_channel();
-
-        let mtc = Self {
-            tx: Arc::new(tx),
-            rx: Arc::new(Mutex::new(rx)),
-            id: Arc::new(RwLock::new(String::new())),
-        };
-
-        thread::spawn({
-            let mtc = mtc.clone();
-            || async move {
-                loop {
-                    match mtc.rx.clone().lock().await.recv().await {
-                        Some(MicrotaskInstruction::Task(nodes, scope, hydrator)) => {
-                            let program = Program::from((nodes, hydrator));
-                            let result = program.eval(Some(scope)).unwrap();
-                            mtc.tx
-                                .clone()
-                                .send(MicrotaskInstruction::Result(result))
-                                .unwrap();
-                        }
-                        _ => {}
-                    }
-                }
-            }
-        });
-
-        mtc
-    }
+    pub fn new(index: usize) -> Self {
+        let (tx, mut rx) = unbounded_channel();
 
-    pub fn queue_microtask<'a>(
-        &self,
-        task: Vec<ContextualNode<'a>>,
-        scope: MutScope<'a>,
-        hydrator: Hydrator,
-    ) -> ContextualObject<'a> {
-        let uuid = uuid::Uuid::new_v4().to_string();
-
-        let task = Box::leak(Box::new(task)).to_owned();
-        let task = unsafe {
-            std::mem::transmute::<Vec<ContextualNode<'a>>, Vec<ContextualNode<'static>>>(task)
-        };
+        thread::Builder::new()
+            .stack_size(TASK_STACK)
+            .spawn(move || {
+                while let Some(MicrotaskInstruction::Task(task)) = rx.blocking_recv() {
+                    let id = task.id.clone();
+                    let result = run(task);
 
-        let scope = Box::leak(Box::new(scope)).to_owned();
-        let scope = unsafe { std::mem::transmute::<MutScope<'a>, MutScope<'static>>(scope) };
-        *self.id.write().unwrap() = uuid.clone();
-
-        self.tx
-            .clone()
-            .send(MicrotaskInstruction::Task(task, scope, hydrator))
+                    let mut scheduler = Microtasker.lock().unwrap();
+                    scheduler.free_channels.push(index);
+                    scheduler.settle(id, result);
+                    scheduler.tick();
+                }
+            })
             .unwrap();
 
-        Object::Promise(String::new(), uuid).anonymous()
+        Self { tx: Arc::new(tx) }
     }
 }
 
-pub struct MicrotaskScheduler<'a> {
+pub struct MicrotaskScheduler {
     pub channels: Vec<MicrotaskChannel>,
-    pub queue: Vec<(Vec<ContextualNode<'a>>, MutScope<'a>, Hydrator)>,
-    pub free_channels: RwLock<Vec<usize>>,
-    pub promises: BTreeMap<String, ContextualObject<'a>>,
+    pub queue: VecDeque<Microtask>,
+    pub free_channels: Vec<usize>,
+    // Tasks handed to a worker that haven't settled yet
+    pub running: BTreeSet<String>,
+    // Promises that a timer has yet to settle or queue
+    pub timers: BTreeSet<String>,
+    pub promises: BTreeMap<String, Result<ContextualObject, Error>>,
+    // Settled promises that something has waited on
+    pub observed: BTreeSet<String>,
+    // The isolate that queued each task or started each timer
+    pub owners: BTreeMap<String, usize>,
 }
 
-impl<'a> MicrotaskScheduler<'a> {
+impl MicrotaskScheduler {
     pub fn new() -> Self {
         let count: usize = env::var("PET_MT_THREADS")
             .unwrap_or("0".to_string())
             .parse()
             .expect("Invalid PET_MT_THREADS value");
 
-        let mut channels = vec![];
-        for _ in 0 as usize..count {
-            channels.push(MicrotaskChannel::new());
-        }
-
         Self {
-            channels,
-            queue: vec![],
-            free_channels: RwLock::new((0..count).collect()),
+            channels: (0..count).map(MicrotaskChannel::new).collect(),
+            queue: VecDeque::new(),
+            free_channels: (0..count).rev().collect(),
+            running: BTreeSet::new(),
+            timers: BTreeSet::new(),
             promises: BTreeMap::new(),
+            observed: BTreeSet::new(),
+            owners: BTreeMap::new(),
         }
     }
 
+    /// Hands queued tasks to whichever workers are free. Without any
+    /// workers, tasks stay queued until they're waited on.
     pub fn tick(&mut self) {
-        if self.free_channels.read().unwrap().is_empty() || self.queue.is_empty() {
-            return;
+        while !self.free_channels.is_empty() && !self.queue.is_empty() {
+            let channel = self.free_channels.pop().unwrap();
+            let task = self.queue.pop_front().unwrap();
+
+            self.running.insert(task.id.clone());
+            self.channels[channel]
+                .tx
+                .send(MicrotaskInstruction::Task(task))
+                .unwrap();
         }
+    }
 
-        let channel = self.free_channels.write().unwrap().pop().unwrap();
-        let (task, scope, hydrator) = self.queue.pop().unwrap();
+    // The first way a promise settles sticks, so a task finishing after
+    // it was cancelled doesn't change anything
+    fn settle(&mut self, id: String, result: Result<ContextualObject, Error>) {
+        self.running.remove(&id);
+        self.timers.remove(&id);
+        self.promises.entry(id).or_insert(result);
+        SETTLED.notify_all();
+    }
 
-        self.channels[channel].queue_microtask(task, scope, hydrator);
+    fn push(&mut self, task: Microtask) {
+        self.queue.push_back(task);
+        self.tick();
+        SETTLED.notify_all();
     }
 
-    pub fn wait(&mut self, id: String) -> ContextualObject<'a> {
+    // Runs a task on a thread of its own, outside of the workers
+    fn start(&mut self, task: Microtask) {
+        self.running.insert(task.id.clone());
+        thread::Builder::new()
+            .stack_size(TASK_STACK)
+            .spawn(move || {
+                let id = task.id.clone();
+                let result = run(task);
+                Microtasker.lock().unwrap().settle(id, result);
+            })
+            .unwrap();
+    }
+
+    // Takes a task back off the queue, so that it can be run right away
+    fn unqueue(&mut self, id: &str) -> Option<Microtask> {
+        let index = self.queue.iter().position(|t| t.id == id)?;
+        self.queue.remove(index)
+    }
+
+    // Takes the next task the running isolate queued off the queue. Tasks
+    // share scopes with the isolate that queued them, so a thread that's
+    // blocked only runs its own.
+    fn unqueue_own(&mut self) -> Option<Microtask> {
+        let isolate = ISOLATE.get();
+        let index = self.queue.iter().position(|t| self.owners.get(&t.id) == Some(&isolate))?;
+        self.queue.remove(index)
+    }
+
+    // Whether the running isolate has tasks or timers yet to settle
+    fn pending_own(&self) -> bool {
+        let isolate = ISOLATE.get();
+        self.running
+            .iter()
+            .chain(self.timers.iter())
+            .any(|id| self.owners.get(id) == Some(&isolate))
+    }
+}
+
+/// Queues `body` to run in `scope` as a task, handing back a promise of
+/// its result.
+pub fn queue_microtask(
+    body: Vec<ContextualNode>,
+    scope: MutScope,
+    typed: &str,
+    hydrator: Hydrator,
+) -> Object {
+    let id = uuid::Uuid::new_v4().to_string();
+
+    queue(
+        id.clone(),
+        Work::Block {
+            typed: typed.to_string(),
+            body,
+            scope,
+            hydrator,
+        },
+    );
+    Object::Promise(typed.to_string(), id)
+}
+
+/// The id of the promise `value`, which had better be one.
+pub fn promise_id(value: &ContextualObject, h: Hydrator) -> Result<String, Error> {
+    match &value.0 {
+        Object::Promise(_, id) => Ok(id.clone()),
+        _ => Err(partial!(
+            "checking types",
+            format!("Expected a promise, found {}", value.0.typed()),
+            "Promises come from async functions and blocks",
+            value.1,
+            h
+        )),
+    }
+}
+
+/// Queues some work for the runtime to do as a task, handing back a
+/// promise of its result.
+pub fn queue_native(
+    work: impl FnOnce() -> Result<ContextualObject, Error> + Send + 'static,
+) -> Object {
+    let id = uuid::Uuid::new_v4().to_string();
+    queue(id.clone(), Work::Native(Box::new(work)));
+    Object::Promise("any".to_string(), id)
+}
+
+/// Blocks until `ready` has something to hand back, running the isolate's
+/// own queued tasks in the meantime, in case they're what it waits on.
+/// Whatever `ready` looks at has to `wake` blocked threads when it changes.
+pub fn block_on<T>(mut ready: impl FnMut() -> Option<T>) -> T {
+    let mut scheduler = Microtasker.lock().unwrap();
+    loop {
+        if let Some(value) = ready() {
+            return value;
+        }
+
+        match scheduler.unqueue_own() {
+            Some(task) => {
+                drop(scheduler);
+                let id = task.id.clone();
+                let result = run(task);
+                scheduler = Microtasker.lock().unwrap();
+                scheduler.settle(id, result);
+            }
+            None => scheduler = SETTLED.wait(scheduler).unwrap(),
+        }
+    }
+}
+
+/// Wakes the threads in `block_on`, so that they look again.
+pub fn wake() {
+    let _scheduler = Microtasker.lock().unwrap();
+    SETTLED.notify_all();
+}
+
+fn queue(id: String, work: Work) {
+    let mut scheduler = Microtasker.lock().unwrap();
+    scheduler.owners.insert(id.clone(), ISOLATE.get());
+    scheduler.push(Microtask { id, work });
+}
+
+/// Marks the promises `ids` as having something waiting on them, so that
+/// their failures aren't reported as uncaught.
+pub fn observe(ids: &[String]) {
+    let mut scheduler = Microtasker.lock().unwrap();
+    scheduler.observed.extend(ids.iter().cloned());
+}
+
+/// Blocks until the promise `id` settles, running its task here if no
+/// worker has picked it up yet.
+pub fn wait(id: &str) -> Result<ContextualObject, Error> {
+    wait_any(&[id.to_string()]).1
+}
+
+/// Blocks until the first of `ids` settles, handing back which one it was
+/// and how it settled. When none of them are running yet, the first that's
+/// still queued is run here, and while they wait on timers, the isolate's
+/// other queued tasks are.
+pub fn wait_any(ids: &[String]) -> (usize, Result<ContextualObject, Error>) {
+    let mut scheduler = Microtasker.lock().unwrap();
+    let (index, result) = loop {
+        if let Some(index) = ids.iter().position(|id| scheduler.promises.contains_key(id)) {
+            break (index, scheduler.promises[&ids[index]].clone());
+        }
+
+        if !ids.iter().any(|id| scheduler.running.contains(id)) {
+            let next = match ids.iter().find_map(|id| scheduler.unqueue(id)) {
+                Some(task) => Some(task),
+                None => scheduler.unqueue_own(),
+            };
+
+            if let Some(task) = next {
+                drop(scheduler);
+                let id = task.id.clone();
+                let result = run(task);
+                scheduler = Microtasker.lock().unwrap();
+                scheduler.settle(id, result);
+                continue;
+            }
+        }
+
+        scheduler = SETTLED.wait(scheduler).unwrap();
+    };
+    scheduler.observed.insert(ids[index].clone());
+    (index, result)
+}
+
+/// Blocks until the promise `id` settles or `limit` passes, whichever is
+/// first. A task that's still queued is started on a thread of its own,
+/// so that it can be timed.
+pub fn wait_for(id: &str, limit: Duration) -> Option<Result<ContextualObject, Error>> {
+    let deadline = Instant::now() + limit;
+    let mut scheduler = Microtasker.lock().unwrap();
+    loop {
+        if let Some(result) = scheduler.promises.get(id) {
+            let result = result.clone();
+            scheduler.observed.insert(id.to_string());
+            return Some(result);
+        }
+
+        if let Some(task) = scheduler.unqueue(id) {
+            scheduler.start(task);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            // Whatever it settles with now, nothing's waiting for it
+            scheduler.observed.insert(id.to_string());
+            return None;
+        }
+        scheduler = SETTLED.wait_timeout(scheduler, deadline - now).unwrap().0;
+    }
+}
+
+/// Settles the promise `id` as cancelled, unless it has settled already.
+/// Queued tasks never run, and running ones are left to finish with
+/// nothing waiting on them.
+pub fn cancel(id: &str, span: Span<'_>, h: Hydrator) -> bool {
+    let mut scheduler = Microtasker.lock().unwrap();
+    if scheduler.promises.contains_key(id) {
+        return false;
+    }
+
+    scheduler.unqueue(id);
+    scheduler.running.remove(id);
+    scheduler.observed.insert(id.to_string());
+
+    let error = partial!("awaiting", "The promise was cancelled", span, h);
+    scheduler.settle(id.to_string(), Err(error));
+    true
+}
+
+/// Runs every task the running isolate still has outstanding, and waits
+/// out its timers, so that none are cut off when it ends. A task that
+/// failed without anything waiting on it fails the isolate.
+pub fn drain() -> Result<(), Error> {
+    let mut scheduler = Microtasker.lock().unwrap();
+    loop {
+        if let Some(task) = scheduler.unqueue_own() {
+            drop(scheduler);
+            let id = task.id.clone();
+            let result = run(task);
+            scheduler = Microtasker.lock().unwrap();
+            scheduler.settle(id, result);
+        } else if scheduler.pending_own() {
+            scheduler = SETTLED.wait(scheduler).unwrap();
+        } else {
+            break;
+        }
+    }
+
+    let scheduler = &mut *scheduler;
+    let isolate = ISOLATE.get();
+    let unobserved = scheduler
+        .promises
+        .iter()
+        .filter(|(id, _)| scheduler.owners.get(*id) == Some(&isolate))
+        .find(|(id, result)| result.is_err() && !scheduler.observed.contains(*id))
+        .map(|(id, _)| id.clone());
+
+    match unobserved {
+        Some(id) => {
+            scheduler.observed.insert(id.clone());
+            scheduler.promises[&id].clone().map(|_| ())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Evaluates `f` knowing whether an `await` is waiting on what it gives.
+pub fn awaited<T>(awaited: bool, f: impl FnOnce() -> T) -> T {
+    let outer = AWAITED.replace(awaited);
+    let result = f();
+    AWAITED.set(outer);
+    result
+}
+
+pub fn is_awaited() -> bool {
+    AWAITED.get()
+}
+
+/// Runs `f` as an isolate of its own, so that the tasks it queues are kept
+/// apart from everyone else's.
+pub fn isolated<T>(f: impl FnOnce() -> T) -> T {
+    let outer = ISOLATE.replace(ISOLATES.fetch_add(1, Ordering::Relaxed));
+    let result = f();
+    ISOLATE.set(outer);
+    result
+}
+
+/// A promise that settles with null once `delay` has passed.
+pub fn sleep(delay: Duration) -> Object {
+    timer("null", move |id| async move {
+        tokio::time::sleep(delay).await;
+        let mut scheduler = Microtasker.lock().unwrap();
+        scheduler.settle(id, Ok(Object::Null.anonymous()));
+    })
+}
+
+/// Queues `work` as a task once `delay` has passed, handing back a promise
+/// of its result.
+pub fn set_timeout(delay: Duration, work: Native) -> Object {
+    timer("any", move |id| async move {
+        tokio::time::sleep(delay).await;
+        let mut scheduler = Microtasker.lock().unwrap();
+
+        // Unless it was cancelled while it waited
+        if scheduler.timers.remove(&id) {
+            scheduler.push(Microtask {
+                id,
+                work: Work::Native(work),
+            });
+        }
+    })
+}
+
+/// Queues `work` as a task every `period`. The promise handed back only
+/// settles when it's cancelled, or when `work` fails.
+pub fn set_interval(
+    period: Duration,
+    work: impl Fn() -> Result<ContextualObject, Error> + Send + Sync + 'static,
+) -> Object {
+    let work = Arc::new(work);
+    timer("any", move |id| async move {
+        let start = tokio::time::Instant::now() + period;
+        let mut ticks = tokio::time::interval_at(start, period);
+        let mut last: Option<String> = None;
+
         loop {
-            if let Some(result) = self.promises.remove(&id) {
-                return result;
+            ticks.tick().await;
+            let mut scheduler = Microtasker.lock().unwrap();
+            if !scheduler.timers.contains(&id) {
+                return;
             }
 
-            self.tick();
+            // Ticks don't pile up behind one that hasn't finished
+            if let Some(tick) = &last {
+                if !scheduler.promises.contains_key(tick) {
+                    continue;
+                }
+                scheduler.promises.remove(tick);
+                scheduler.owners.remove(tick);
+            }
+
+            let tick = uuid::Uuid::new_v4().to_string();
+            let (work, handle) = (work.clone(), id.clone());
+            let owner = scheduler.owners[&id];
+            scheduler.owners.insert(tick.clone(), owner);
+            scheduler.observed.insert(tick.clone());
+            scheduler.push(Microtask {
+                id: tick.clone(),
+                work: Work::Native(Box::new(move || {
+                    if let Err(e) = work() {
+                        Microtasker.lock().unwrap().settle(handle, Err(e));
+                    }
+                    Ok(Object::Null.anonymous())
+                })),
+            });
+            last = Some(tick);
         }
+    })
+}
+
+// Starts a timer on the runtime, pending until it settles or is cancelled
+fn timer<F>(typed: &str, start: impl FnOnce(String) -> F) -> Object
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let id = uuid::Uuid::new_v4().to_string();
+    let mut scheduler = Microtasker.lock().unwrap();
+    scheduler.timers.insert(id.clone());
+    scheduler.owners.insert(id.clone(), ISOLATE.get());
+    drop(scheduler);
+    TIMERS.spawn(start(id.clone()));
+    Object::Promise(typed.to_string(), id)
+}
+
+// Does a task's work as the isolate that queued it
+fn run(task: Microtask) -> Result<ContextualObject, Error> {
+    let owner = Microtasker.lock().unwrap().owners.get(&task.id).copied();
+    let outer = ISOLATE.replace(owner.unwrap_or_default());
+    let result = perform(task.work);
+    ISOLATE.set(outer);
+    result
+}
+
+// Blocks are held to the type they promised
+fn perform(work: Work) -> Result<ContextualObject, Error> {
+    let (typed, body, scope, h) = match work {
+        Work::Block {
+            typed,
+            body,
+            scope,
+            hydrator,
+        } => (typed, body, scope, hydrator),
+        Work::Native(work) => return work(),
+    };
+
+    let result = perform_block(&typed, &body, scope.clone(), h);
+    Scope::release(scope);
+    result
+}
+
+fn perform_block(
+    typed: &str,
+    body: &[ContextualNode],
+    scope: MutScope,
+    h: Hydrator,
+) -> Result<ContextualObject, Error> {
+    let result = step_block(body, scope.clone(), h.clone())?;
+    if let Some(signal) = scope.write().unwrap().take_signal() {
+        return Err(unclaimed_signal(signal, h));
     }
+    let (result, returned) = match result.0 {
+        Object::Return(expr) => (*expr, result.1),
+        _ => (result.clone(), result.1),
+    };
+
+    result.expect_type(
+        typed,
+        format!("The task promised {typed}"),
+        returned,
+        &scope.read().unwrap(),
+        h,
+    )?;
+    Ok(result)
 }
@@ -1,6 +1,6 @@
 use pest::Span;
 
-pub fn extend<'a>(spans: &[Span<'a>]) -> Span<'a> {
+pub fn extend(spans: &[Span<'static>]) -> Span<'static> {
     if spans.is_empty() {
         return Span::new("", 0, 0).unwrap();
     }
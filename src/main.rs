@@ -1,10 +1,9 @@
 #![allow(dead_code)]
 
-use ast::Program;
-use clap::{arg, command, Parser as CommandParser};
-use eval::repl;
-use itertools::Itertools;
+use clap::{arg, command, Parser as CommandParser, Subcommand};
 use miette::bail;
+use eval::{modules, repl};
+use itertools::Itertools;
 use pest_derive::Parser;
 
 #[derive(Parser)]
@@ -12,17 +11,38 @@ use pest_derive::Parser;
 struct PetParser;
 
 #[derive(CommandParser, Debug)]
-#[command(version = "1.0.0", about = "Interpreter for petal")]
+#[command(
+    version = "1.0.0",
+    about = "Interpreter for petal",
+    args_conflicts_with_subcommands = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(name = "FILES", help = "The files to run")]
     files: Vec<String>,
     #[arg(short = 'q', default_value = "false", help = "Quiet mode")]
     quiet: bool,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Checks the files against their type annotations without running them
+    Check {
+        #[arg(name = "FILES", help = "The files to check")]
+        files: Vec<String>,
+    },
+    /// Prints the doc comments of the files, under the items they document
+    Doc {
+        #[arg(name = "FILES", help = "The files to document")]
+        files: Vec<String>,
+    },
+}
+
 #[macro_use]
 mod errors;
 mod ast;
+mod checker;
 mod eval;
 mod helpers;
 mod object;
@@ -32,12 +52,40 @@ mod types;
 fn main() -> miette::Result<()> {
     let args = Args::parse();
 
+    if let Some(Command::Check { files }) = args.command {
+        let mut problems = 0;
+        for path in expand(&files) {
+            problems += modules::check_entry(&path)?;
+        }
+
+        if problems > 0 {
+            bail!("Found {problems} type errors");
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Doc { files }) = args.command {
+        for path in expand(&files) {
+            modules::doc_entry(&path)?;
+        }
+        return Ok(());
+    }
+
     if args.files.is_empty() {
         repl::repl();
         return Ok(());
     };
 
-    let files = args.files.iter().flat_map(|loc| {
+    // Each file runs as a module of its own, sharing only what it imports
+    expand(&args.files)
+        .map(|path| modules::run_entry(&path))
+        .try_collect::<_, Vec<()>, _>()?;
+
+    Ok(())
+}
+
+fn expand(files: &[String]) -> impl Iterator<Item = String> + '_ {
+    files.iter().flat_map(|loc| {
         if !loc.contains('*') {
             vec![loc.to_string()]
         } else {
@@ -47,22 +95,5 @@ fn main() -> miette::Result<()> {
                 .map(|p| p.into_os_string().to_str().unwrap().to_string())
                 .collect::<Vec<_>>()
         }
-    });
-
-    let scope = scope::Scope::new("#pet.global");
-
-    files
-        .map(|path| {
-            let content = std::fs::read_to_string(&path).unwrap();
-            match Program::make(content, Some(path)).map(|p| p.eval(Some(scope.clone()))) {
-                Err(e) => bail!(e),
-                Ok(v) => match v {
-                    Ok(_) => Ok(()),
-                    Err(e) => bail!(e),
-                },
-            }
-        })
-        .try_collect()?;
-
-    Ok(())
+    })
 }
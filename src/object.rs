@@ -1,55 +1,100 @@
 use std::{collections::BTreeMap, fmt::Display};
 
+use itertools::Itertools;
 use owo_colors::OwoColorize;
 use pest::Span;
 
 use crate::{
-    ast::ContextualNode,
+    ast::{ContextualNode, VariantShape},
     errors::{Error, Hydrator},
-    eval::{repl::ReplDisplay, step},
+    eval::{isolates::Channel, iterators::Lazy, repl::ReplDisplay, step_block, tasks, unclaimed_signal},
     helpers::extend,
-    scope::{MutScope, Scope},
+    scope::{Captured, MutScope, Scope},
     types::{Float, Int, Num, VariablySized},
 };
 
 #[derive(Debug, PartialEq, Clone, PartialOrd, Eq, Ord)]
-pub enum Object<'a> {
+pub enum Object {
     Integer(Int),
     Float(Float),
     Bool(bool),
     String(String),
-    Array(Vec<ContextualObject<'a>>),
-    Map(BTreeMap<ContextualObject<'a>, ContextualObject<'a>>),
-    Return(Box<ContextualObject<'a>>),
+    Array(Vec<ContextualObject>),
+    Map(BTreeMap<ContextualObject, ContextualObject>),
+    Return(Box<ContextualObject>),
     Promise(String, String),
-    // Function(Vec<Expr>, Block, Scope),
+    Function(
+        String,
+        Vec<(String, String)>,
+        Option<String>,
+        Vec<ContextualNode>,
+        Captured,
+    ),
     Builtin(
         String,
         bool,
         fn(
-            Vec<ContextualObject<'a>>,
+            Vec<ContextualObject>,
             Hydrator,
-            MutScope<'a>,
-        ) -> Result<ContextualObject<'a>, Error>,
+            MutScope,
+        ) -> Result<ContextualObject, Error>,
+    ),
+    Lambda(
+        Vec<(String, String)>,
+        Option<String>,
+        Vec<ContextualNode>,
+        Captured,
     ),
-    Lambda(Vec<String>, Option<String>, Vec<ContextualNode<'a>>),
+    StructType(String, Vec<(String, String)>),
+    Trait(String, Vec<String>, BTreeMap<String, ContextualObject>),
+    EnumType(String, Vec<(String, VariantShape)>),
+    VariantConstructor(String, String, VariantShape),
+    Enum(String, String, Option<Box<ContextualObject>>),
+    Struct(String, Vec<(String, ContextualObject)>),
+    Range(Int, Int, bool),
+    Module(String, BTreeMap<String, ContextualObject>),
+    Iterator(Lazy),
+    Channel(Channel),
     Null,
 }
 
-impl<'a> Object<'a> {
-    pub fn provide_context(&self, span: Span<'a>) -> ContextualObject<'a> {
+impl Object {
+    pub fn provide_context(&self, span: Span<'static>) -> ContextualObject {
         ContextualObject(self.clone(), span)
     }
 
-    pub fn anonymous(self) -> ContextualObject<'a> {
+    pub fn anonymous(self) -> ContextualObject {
         self.provide_context(Span::new("", 0, 0).unwrap())
     }
+
+    /// Whether this value can be used where `typed` is declared. `any` takes
+    /// everything, ints pass for floats, and a trait is met by any type that
+    /// implements its required methods.
+    pub fn conforms(&self, typed: &str, scope: &Scope) -> bool {
+        match (typed, self) {
+            ("any", _) | ("float", Object::Integer(_)) => true,
+            (
+                "function" | "lambda",
+                Object::Function(..) | Object::Lambda(..) | Object::Builtin(..),
+            ) => true,
+            (typed, _) if self.typed() == typed => true,
+            (typed, _) => {
+                match scope.get(typed) {
+                    Some(ContextualObject(Object::Trait(_, required, _), _)) => {
+                        let methods = scope.get_methods(&self.typed());
+                        required.iter().all(|m| methods.contains_key(m))
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Eq)]
-pub struct ContextualObject<'a>(pub Object<'a>, pub Span<'a>);
+pub struct ContextualObject(pub Object, pub Span<'static>);
 
-impl PartialOrd for ContextualObject<'_> {
+impl PartialOrd for ContextualObject {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match self.0.partial_cmp(&other.0) {
             Some(core::cmp::Ordering::Equal) => {
@@ -60,18 +105,18 @@ impl PartialOrd for ContextualObject<'_> {
     }
 }
 
-impl Ord for ContextualObject<'_> {
+impl Ord for ContextualObject {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.partial_cmp(other).unwrap()
     }
 }
 
-impl<'a> Object<'a> {
+impl Object {
     pub fn coerce(
-        a: ContextualObject<'a>,
-        b: ContextualObject<'a>,
+        a: ContextualObject,
+        b: ContextualObject,
         h: Hydrator,
-    ) -> Result<(ContextualObject<'a>, ContextualObject<'a>), Error> {
+    ) -> Result<(ContextualObject, ContextualObject), Error> {
         if std::mem::discriminant(&a.0) == std::mem::discriminant(&b.0) {
             return Ok((a, b));
         }
@@ -121,14 +166,25 @@ impl<'a> Object<'a> {
             Object::Return(_) => "return",
             Object::Builtin(..) => "builtin",
             Object::Lambda(..) => "lambda",
+            Object::Function(..) => "function",
             Object::Promise(..) => "promise",
+            Object::StructType(..) => "struct",
+            Object::Trait(..) => "trait",
+            Object::EnumType(..) => "enum",
+            Object::VariantConstructor(..) => "constructor",
+            Object::Enum(typed, ..) => typed,
+            Object::Struct(typed, _) => typed,
+            Object::Range(..) => "range",
+            Object::Module(..) => "module",
+            Object::Iterator(..) => "iterator",
+            Object::Channel(..) => "channel",
             Object::Null => "null",
         }
         .to_string()
     }
 }
 
-impl Display for Object<'_> {
+impl Display for Object {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Object::Integer(v) => f.write_str(v.to_max_value().to_string().as_str()),
@@ -141,13 +197,29 @@ impl Display for Object<'_> {
             Object::Lambda(args, typed, ..) => write!(
                 f,
                 "#pet.lambda({args}): {typed}",
-                args = args.join(", "),
+                args = args.iter().map(|(a, _)| a.as_str()).join(", "),
                 typed = match typed {
                     Some(t) => format!(" -> {}", t),
                     None => "".to_string(),
                 }
             ),
+            Object::Function(name, args, typed, ..) => write!(
+                f,
+                "#pet.fn({name}({args})){typed}",
+                args = args.iter().map(|(a, _)| a.as_str()).join(", "),
+                typed = match typed {
+                    Some(t) => format!(": {}", t),
+                    None => "".to_string(),
+                }
+            ),
             Object::Null => write!(f, "null"),
+            Object::Range(start, end, inclusive) => write!(
+                f,
+                "{}{}{}",
+                start.to_max_value(),
+                if *inclusive { "..=" } else { ".." },
+                end.to_max_value()
+            ),
 
             Object::Promise(typed, ..) => write!(f, "#pet.promise({typed})"),
 
@@ -168,11 +240,38 @@ impl Display for Object<'_> {
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
+
+            Object::StructType(name, ..) => write!(f, "#pet.struct({name})"),
+            Object::Trait(name, ..) => write!(f, "#pet.trait({name})"),
+            Object::Module(path, _) => write!(f, "#pet.module({path})"),
+            Object::Iterator(..) => write!(f, "#pet.iterator"),
+            Object::Channel(..) => write!(f, "#pet.channel"),
+            Object::EnumType(name, ..) => write!(f, "#pet.enum({name})"),
+            Object::VariantConstructor(name, variant, ..) => {
+                write!(f, "#pet.constructor({name}.{variant})")
+            }
+            Object::Enum(name, variant, payload) => match payload.as_deref() {
+                Some(ContextualObject(Object::Array(v), _)) => write!(
+                    f,
+                    "{name}.{variant}({})",
+                    v.iter().map(|v| v.0.to_string()).join(", ")
+                ),
+                Some(v) => write!(f, "{name}.{}", v.0),
+                None => write!(f, "{name}.{variant}"),
+            },
+            Object::Struct(name, fields) => write!(
+                f,
+                "{name} {{ {} }}",
+                fields
+                    .iter()
+                    .map(|(k, v)| format!("{k}: {}", v.0))
+                    .join(", ")
+            ),
         }
     }
 }
 
-impl ReplDisplay for Object<'_> {
+impl ReplDisplay for Object {
     fn pretty_print(&self) -> String {
         match self {
             Object::Integer(v) => v.to_max_value().to_string().yellow().to_string(),
@@ -191,14 +290,12 @@ impl ReplDisplay for Object<'_> {
             ),
             Object::Map(v) => format!(
                 "{}{ma}{}",
-                "[".blue(),
-                "]".blue(),
+                "{".blue(),
+                "}".blue(),
                 ma = v
                     .iter()
                     .map(|i| format!(
-                        "{}{ke}: {va}{}",
-                        "{".blue(),
-                        "}".blue(),
+                        "{ke}: {va}",
                         ke = i.0 .0.pretty_print(),
                         va = i.1 .0.pretty_print()
                     ))
@@ -214,12 +311,22 @@ impl ReplDisplay for Object<'_> {
             Object::Lambda(args, typed, ..) => format!(
                 "{}({}): {}",
                 "#pet.lambda".purple().to_string(),
-                format!("{}", args.join(", ")).magenta().to_string(),
+                args.iter().map(|(a, _)| a.as_str()).join(", ").magenta().to_string(),
                 match typed {
                     Some(t) => format!("{}", t.magenta().to_string()),
                     None => "".to_string(),
                 }
             ),
+            Object::Function(name, args, typed, ..) => format!(
+                "{}({}({})){}",
+                "#pet.fn".purple(),
+                name.magenta(),
+                args.iter().map(|(a, _)| a.as_str()).join(", "),
+                match typed {
+                    Some(t) => format!(": {}", t.magenta()),
+                    None => "".to_string(),
+                }
+            ),
 
             Object::Promise(typed, ..) => format!(
                 "{}({})",
@@ -227,49 +334,132 @@ impl ReplDisplay for Object<'_> {
                 typed.magenta().to_string()
             ),
 
+            Object::StructType(name, ..) => {
+                format!("{}({})", "#pet.struct".purple(), name.magenta())
+            }
+            Object::Trait(name, ..) => format!("{}({})", "#pet.trait".purple(), name.magenta()),
+            Object::Module(path, _) => format!("{}({})", "#pet.module".purple(), path.magenta()),
+            Object::Iterator(..) => "#pet.iterator".purple().to_string(),
+            Object::Channel(..) => "#pet.channel".purple().to_string(),
+            Object::EnumType(name, ..) => format!("{}({})", "#pet.enum".purple(), name.magenta()),
+            Object::VariantConstructor(name, variant, ..) => format!(
+                "{}({})",
+                "#pet.constructor".purple(),
+                format!("{name}.{variant}").magenta()
+            ),
+            Object::Enum(name, variant, payload) => match payload.as_deref() {
+                Some(ContextualObject(Object::Array(v), _)) => format!(
+                    "{}{}{}{}",
+                    format!("{name}.{variant}").purple(),
+                    "(".blue(),
+                    v.iter().map(|v| v.0.pretty_print()).join(", "),
+                    ")".blue()
+                ),
+                Some(v) => format!("{}{}", format!("{name}.").purple(), v.0.pretty_print()),
+                None => format!("{name}.{variant}").purple().to_string(),
+            },
+            Object::Struct(name, fields) => format!(
+                "{} {}{}{}",
+                name.purple(),
+                "{ ".blue(),
+                fields
+                    .iter()
+                    .map(|(k, v)| format!("{k}: {}", v.0.pretty_print()))
+                    .join(", "),
+                " }".blue()
+            ),
+
             Object::Null => "null".magenta().to_string(),
+            Object::Range(start, end, inclusive) => format!(
+                "{}{}{}",
+                start.to_max_value().to_string().yellow(),
+                if *inclusive { "..=" } else { ".." }.blue(),
+                end.to_max_value().to_string().yellow()
+            ),
         }
     }
 }
 
-impl<'a> ContextualObject<'a> {
-    pub fn call(
+impl ContextualObject {
+    /// Fails at `span` unless this value conforms to the declared `typed`.
+    pub fn expect_type(
         &self,
-        mut args: Vec<ContextualObject<'a>>,
-        scope: MutScope<'a>,
+        typed: &str,
+        hint: String,
+        span: Span<'static>,
+        scope: &Scope,
         h: Hydrator,
-    ) -> Result<ContextualObject<'a>, Error> {
-        let call_scope = Scope::new_child(scope.clone(), "#pet.call");
+    ) -> Result<(), Error> {
+        match self.0.conforms(typed, scope) {
+            true => Ok(()),
+            false => Err(partial!(
+                "checking types",
+                format!("Expected {typed}, found {}", self.0.typed()),
+                hint,
+                span,
+                h
+            )),
+        }
+    }
 
+    pub fn call(
+        &self,
+        mut args: Vec<ContextualObject>,
+        scope: MutScope,
+        h: Hydrator,
+    ) -> Result<ContextualObject, Error> {
         match &self.0 {
-            Object::Lambda(fn_args, _, body) => {
-                if fn_args.len() != args.len() {
-                    return Err(partial!(
+            Object::Lambda(fn_args, ret, body, captured) => {
+                let call_scope = Scope::new_child(captured.0.clone(), "#pet.call");
+                let result =
+                    self.call_body("the lambda", fn_args, ret, body, args, call_scope.clone(), h);
+                Scope::release(call_scope);
+                result
+            }
+            Object::Function(name, fn_args, ret, body, captured) => {
+                let call_scope = Scope::new_child(captured.0.clone(), "#pet.call");
+                let mut fn_args = fn_args.clone();
+
+                if fn_args.first().is_some_and(|(a, _)| a == "self") {
+                    let slf = scope.read().unwrap().get_self().ok_or(partial!(
                         "evaluating function call",
-                        format!("Expected {} arguments, got {}", fn_args.len(), args.len()),
-                        self.1.clone(),
+                        "No self provided for method call".to_string(),
+                        self.1,
                         h.clone()
-                    ));
-                }
+                    ))?;
 
-                for (value, name) in args.into_iter().zip(fn_args.into_iter()) {
-                    call_scope
-                        .write()
-                        .unwrap()
-                        .set(&name, value, self.1, h.clone())?;
+                    call_scope.write().unwrap().bind_self(slf);
+                    fn_args.remove(0);
                 }
 
-                let mut result: ContextualObject = Object::Null.anonymous();
-                for node in body {
-                    result = step(&node, call_scope.clone(), h.clone())?;
-                    if let Object::Return(expr) = &result.0 {
-                        result = *expr.clone();
-                        break;
-                    }
+                let result = self.call_body(name, &fn_args, ret, body, args, call_scope.clone(), h);
+                Scope::release(call_scope);
+                result
+            }
+            Object::VariantConstructor(name, variant, VariantShape::Tuple(types)) => {
+                if types.len() != args.len() {
+                    return Err(partial!(
+                        "evaluating function call",
+                        format!("Expected {} arguments, got {}", types.len(), args.len()),
+                        self.1,
+                        h.clone()
+                    ));
                 }
 
-                Ok(result)
+                Ok(Object::Enum(
+                    name.clone(),
+                    variant.clone(),
+                    Some(Box::new(Object::Array(args).provide_context(self.1))),
+                )
+                .provide_context(self.1))
             }
+            Object::VariantConstructor(name, variant, _) => Err(partial!(
+                "evaluating function call",
+                format!("{name}.{variant} can't be called like a function"),
+                format!("Try {name}.{variant} {{ .. }} instead"),
+                self.1,
+                h.clone()
+            )),
             Object::Builtin(_, needs_self, f) => {
                 if *needs_self {
                     let slf = scope.read().unwrap().get_self().ok_or(partial!(
@@ -293,4 +483,61 @@ impl<'a> ContextualObject<'a> {
             )),
         }
     }
+
+    #[allow(clippy::too_many_arguments)]
+    fn call_body(
+        &self,
+        name: &str,
+        fn_args: &[(String, String)],
+        ret: &Option<String>,
+        body: &[ContextualNode],
+        args: Vec<ContextualObject>,
+        call_scope: MutScope,
+        h: Hydrator,
+    ) -> Result<ContextualObject, Error> {
+        if fn_args.len() != args.len() {
+            return Err(partial!(
+                "evaluating function call",
+                format!("Expected {} arguments, got {}", fn_args.len(), args.len()),
+                self.1.clone(),
+                h.clone()
+            ));
+        }
+
+        for (value, (arg, typed)) in args.into_iter().zip(fn_args.iter()) {
+            value.expect_type(
+                typed,
+                format!("{arg} is declared as {typed} in {name}"),
+                value.1,
+                &call_scope.read().unwrap(),
+                h.clone(),
+            )?;
+            let mut call_scope = call_scope.write().unwrap();
+            call_scope.set(arg, value, self.1, h.clone())?;
+            call_scope.declare_type(arg, typed);
+        }
+
+        // An `await` on the call doesn't reach into what the body does
+        let result = tasks::awaited(false, || step_block(body, call_scope.clone(), h.clone()))?;
+        if let Some(signal) = call_scope.write().unwrap().take_signal() {
+            return Err(unclaimed_signal(signal, h));
+        }
+        // Values that come out of builtins have no span of their own, so a
+        // mismatch is blamed on the `return`, or the function without one
+        let (result, returned) = match result.0 {
+            Object::Return(expr) => (*expr, result.1),
+            _ => (result, self.1),
+        };
+
+        if let Some(typed) = ret {
+            result.expect_type(
+                typed,
+                format!("{name} is declared to return {typed}"),
+                returned,
+                &call_scope.read().unwrap(),
+                h,
+            )?;
+        }
+        Ok(result)
+    }
 }
@@ -1,8 +1,13 @@
-use std::{collections::BTreeMap, sync::{Arc, RwLock}};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
 
 use pest::Span;
 
 use crate::{
+    ast::VariantShape,
     errors::{Error, Hydrator},
     eval::{
         builtins::get_builtin,
@@ -11,36 +16,230 @@ use crate::{
     object::{ContextualObject, Object},
 };
 
-pub type MutScope<'a> = Arc<RwLock<Scope<'a>>>;
+pub type MutScope = Arc<RwLock<Scope>>;
+
+/// A scope held onto by a closure. Captured scopes are compared by identity,
+/// which keeps the objects holding them orderable without walking the scope.
+#[derive(Clone)]
+pub struct Captured(pub MutScope);
+
+impl Debug for Captured {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.try_read() {
+            Ok(scope) => write!(f, "Captured({})", scope.name),
+            Err(_) => write!(f, "Captured(..)"),
+        }
+    }
+}
+
+impl PartialEq for Captured {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Captured {}
+
+impl PartialOrd for Captured {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Captured {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        Arc::as_ptr(&self.0).cmp(&Arc::as_ptr(&other.0))
+    }
+}
+
+/// A pending `break` or `continue`. It is raised on the innermost function
+/// frame, and stops every block it passes through until a loop claims it.
+#[derive(Debug, Clone)]
+pub enum Signal {
+    Break(Option<String>, ContextualObject, Span<'static>),
+    Continue(Option<String>, Span<'static>),
+}
+
+impl Signal {
+    pub fn label(&self) -> Option<&String> {
+        match self {
+            Signal::Break(label, ..) | Signal::Continue(label, _) => label.as_ref(),
+        }
+    }
+
+    pub fn span(&self) -> Span<'static> {
+        match self {
+            Signal::Break(.., span) | Signal::Continue(_, span) => *span,
+        }
+    }
+
+    // Unlabeled signals go to the innermost loop
+    pub fn targets(&self, label: &Option<String>) -> bool {
+        self.label().is_none() || self.label() == label.as_ref()
+    }
+}
 
 #[derive(Debug, Clone)]
-pub struct Scope<'a> {
+pub struct Scope {
     pub name: String,
-    store: BTreeMap<String, ContextualObject<'a>>,
-    parent: Option<MutScope<'a>>,
-    slf: Option<ContextualObject<'a>>,
+    store: BTreeMap<String, ContextualObject>,
+    // The types bindings were declared with, which assignments have to keep
+    types: BTreeMap<String, String>,
+    parent: Option<MutScope>,
+    slf: Option<ContextualObject>,
+    methods: BTreeMap<String, BTreeMap<String, ContextualObject>>,
+    trait_methods: BTreeMap<String, BTreeMap<String, ContextualObject>>,
+    signal: Option<Signal>,
+    exports: Vec<String>,
 }
 
-impl<'a> Scope<'a> {
-    pub fn new(name: &str) -> MutScope<'a> {
+impl Scope {
+    pub fn new(name: &str) -> MutScope {
         Arc::new(RwLock::new(Scope {
             name: name.to_string(),
             store: BTreeMap::new(),
+            types: BTreeMap::new(),
             parent: None,
             slf: None,
+            methods: BTreeMap::new(),
+            trait_methods: BTreeMap::new(),
+            signal: None,
+            exports: Vec::new(),
         }))
     }
 
-    pub fn new_child(parent: MutScope<'a>, name: &str) -> MutScope<'a> {
+    pub fn new_child(parent: MutScope, name: &str) -> MutScope {
         Arc::new(RwLock::new(Scope {
             name: name.to_string(),
             store: BTreeMap::new(),
+            types: BTreeMap::new(),
             parent: Some(parent),
             slf: None,
+            methods: BTreeMap::new(),
+            trait_methods: BTreeMap::new(),
+            signal: None,
+            exports: Vec::new(),
         }))
     }
 
-    pub fn get(&self, ident: &str) -> Option<ContextualObject<'a>> {
+    /// A copy of this scope under `parent`, with every value passed through
+    /// `copy` along with its name. Values that `copy` turns down are left
+    /// out, and any pending signal is dropped.
+    pub fn copy_with(
+        &self,
+        parent: Option<MutScope>,
+        mut copy: impl FnMut(&str, &ContextualObject) -> Option<ContextualObject>,
+    ) -> Scope {
+        let mut copy_all = |values: &BTreeMap<String, ContextualObject>| {
+            values
+                .iter()
+                .filter_map(|(k, v)| Some((k.clone(), copy(k, v)?)))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let store = copy_all(&self.store);
+        let methods = self.methods.iter().map(|(t, m)| (t.clone(), copy_all(m))).collect();
+        let trait_methods = self
+            .trait_methods
+            .iter()
+            .map(|(t, m)| (t.clone(), copy_all(m)))
+            .collect();
+
+        Scope {
+            name: self.name.clone(),
+            store,
+            types: self.types.clone(),
+            parent,
+            slf: self.slf.as_ref().and_then(|v| copy("self", v)),
+            methods,
+            trait_methods,
+            signal: None,
+            exports: self.exports.clone(),
+        }
+    }
+
+    /// Clears out a scope that's being left, when the only things still
+    /// holding it are closures it holds itself, like a lambda bound in the
+    /// frame it captures. Those are cycles that would otherwise keep the
+    /// scope, and everything in it, around for good. Scopes below it that
+    /// only its own closures hold go with it.
+    pub fn release(scope: MutScope) {
+        // Nothing captured it, so it goes when it's dropped
+        if Arc::strong_count(&scope) == 1 {
+            return;
+        }
+
+        let root = Arc::as_ptr(&scope);
+        // Each scope found, with how many references to it were found
+        // inside the scopes found. The clone kept here is one more.
+        let mut found = BTreeMap::from([(root, (scope, 0))]);
+        let mut unseen = vec![root];
+
+        while let Some(next) = unseen.pop() {
+            let mut captured = Vec::new();
+            found[&next].0.read().unwrap().captured(&mut captured);
+
+            for held in captured {
+                // Scopes outside of the one being left aren't its to clear
+                let mut chain = vec![held];
+                loop {
+                    let last = chain.last().unwrap();
+                    if Arc::as_ptr(last) == root {
+                        break;
+                    }
+                    let parent = last.read().unwrap().parent();
+                    match parent {
+                        Some(parent) => chain.push(parent),
+                        None => break,
+                    }
+                }
+                if Arc::as_ptr(chain.last().unwrap()) != root {
+                    continue;
+                }
+
+                // The captured scope is referred to by the closure, and every
+                // scope on the way up by the one below it
+                for scope in chain {
+                    let ptr = Arc::as_ptr(&scope);
+                    let seen = found.contains_key(&ptr);
+                    found.entry(ptr).or_insert_with(|| (scope, 0)).1 += 1;
+                    if seen {
+                        break;
+                    }
+                    unseen.push(ptr);
+                }
+            }
+        }
+
+        if found.values().all(|(scope, refs)| Arc::strong_count(scope) == refs + 1) {
+            for (scope, _) in found.values() {
+                let mut scope = scope.write().unwrap();
+                scope.store.clear();
+                scope.methods.clear();
+                scope.trait_methods.clear();
+                scope.slf = None;
+            }
+        }
+    }
+
+    // Collects the scopes captured by closures among this scope's values
+    fn captured(&self, found: &mut Vec<MutScope>) {
+        let methods = self.methods.values().chain(self.trait_methods.values());
+        let values = self
+            .store
+            .values()
+            .chain(self.slf.iter())
+            .chain(methods.flat_map(|m| m.values()));
+        for value in values {
+            captured_by(&value.0, found);
+        }
+    }
+
+    pub fn parent(&self) -> Option<MutScope> {
+        self.parent.clone()
+    }
+
+    pub fn get(&self, ident: &str) -> Option<ContextualObject> {
         match self.store.get(ident) {
             Some(obj) => Some(obj.clone()),
             None => match &self.parent.as_ref().and_then(|a| match self.name.as_str() {
@@ -53,7 +252,7 @@ impl<'a> Scope<'a> {
         }
     }
 
-    pub fn set(&mut self, ident: &str, obj: ContextualObject<'a>, s: Span, h: Hydrator) -> Result<(), Error> {
+    pub fn set(&mut self, ident: &str, obj: ContextualObject, s: Span, h: Hydrator) -> Result<(), Error> {
         if self.store.contains_key(ident) {
             return Err(partial!(
                 "setting variable",
@@ -68,13 +267,17 @@ impl<'a> Scope<'a> {
         Ok(())
     }
 
-    pub fn assign(&mut self, ident: &str, obj: ContextualObject<'a>, s: Span, h: Hydrator) -> Result<(), Error> {
-        match self.store.get_mut(ident) {
-            Some(o) => {
-                *o = obj;
+    pub fn assign(&mut self, ident: &str, obj: ContextualObject, s: Span<'static>, h: Hydrator) -> Result<(), Error> {
+        match self.store.contains_key(ident) {
+            true => {
+                if let Some(typed) = self.types.get(ident) {
+                    let hint = format!("{ident} is declared as {typed}");
+                    obj.expect_type(typed, hint, s, self, h)?;
+                }
+                self.store.insert(ident.to_string(), obj);
                 Ok(())
             }
-            None => match &self.parent {
+            false => match &self.parent {
                 Some(parent) => parent.write().unwrap().assign(ident, obj, s, h),
                 None => Err(partial!(
                     "assigning variable",
@@ -87,11 +290,111 @@ impl<'a> Scope<'a> {
         }
     }
 
-    pub fn force_set(&mut self, ident: &str, obj: ContextualObject<'a>) {
+    pub fn declare_type(&mut self, ident: &str, typed: &str) {
+        self.types.insert(ident.to_string(), typed.to_string());
+    }
+
+    pub fn force_set(&mut self, ident: &str, obj: ContextualObject) {
         (*self).store.insert(ident.to_string(), obj);
     }
 
-    pub fn get_self(&self) -> Option<ContextualObject<'a>> {
+    pub fn bind_self(&mut self, obj: ContextualObject) {
+        self.force_set("self", obj.clone());
+        self.slf = Some(obj);
+    }
+
+    pub fn export(&mut self, ident: &str) {
+        self.exports.push(ident.to_string());
+    }
+
+    pub fn is_exported(&self, ident: &str) -> bool {
+        self.exports.iter().any(|e| e == ident)
+    }
+
+    // What an `import` of the module this scope ran gets to see
+    pub fn exports(&self) -> BTreeMap<String, ContextualObject> {
+        self.exports
+            .iter()
+            .filter_map(|e| self.store.get(e).map(|v| (e.clone(), v.clone())))
+            .collect()
+    }
+
+    // Methods travel with their types, so importing a module brings its impls along
+    pub fn adopt_methods(&mut self, module: &Scope) {
+        for (typed, methods) in module.methods.iter() {
+            self.methods.entry(typed.clone()).or_default().extend(methods.clone());
+        }
+        for (typed, methods) in module.trait_methods.iter() {
+            self.trait_methods.entry(typed.clone()).or_default().extend(methods.clone());
+        }
+    }
+
+    pub fn define_method(&mut self, typed: &str, ident: &str, method: ContextualObject) {
+        self.methods
+            .entry(typed.to_string())
+            .or_default()
+            .insert(ident.to_string(), method);
+    }
+
+    pub fn define_trait_method(&mut self, typed: &str, ident: &str, method: ContextualObject) {
+        self.trait_methods
+            .entry(typed.to_string())
+            .or_default()
+            .insert(ident.to_string(), method);
+    }
+
+    // Inherent methods win over the ones a type gets from its traits
+    pub fn get_methods(&self, typed: &str) -> BTreeMap<String, ContextualObject> {
+        let mut methods = self.collect_methods(typed, |s| &s.trait_methods);
+        methods.extend(self.collect_methods(typed, |s| &s.methods));
+        methods
+    }
+
+    // Methods closer to this scope shadow the ones further up
+    fn collect_methods(
+        &self,
+        typed: &str,
+        pick: for<'s> fn(&'s Scope) -> &'s BTreeMap<String, BTreeMap<String, ContextualObject>>,
+    ) -> BTreeMap<String, ContextualObject> {
+        let mut methods = match &self.parent {
+            Some(parent) => parent.read().unwrap().collect_methods(typed, pick),
+            None => BTreeMap::new(),
+        };
+
+        if let Some(own) = pick(self).get(typed) {
+            methods.extend(own.clone());
+        }
+
+        methods
+    }
+
+    // Signals live on the frame of the function being run, or the root scope
+    fn is_frame(&self) -> bool {
+        self.name == "#pet.call" || self.parent.is_none()
+    }
+
+    pub fn raise(&mut self, signal: Signal) {
+        match &self.parent {
+            Some(parent) if !self.is_frame() => parent.write().unwrap().raise(signal),
+            _ => self.signal = Some(signal),
+        }
+    }
+
+    pub fn signal(&self) -> Option<Signal> {
+        match &self.parent {
+            Some(parent) if !self.is_frame() => parent.read().unwrap().signal(),
+            _ => self.signal.clone(),
+        }
+    }
+
+    pub fn take_signal(&mut self) -> Option<Signal> {
+        match &self.parent {
+            Some(parent) if !self.is_frame() => parent.write().unwrap().take_signal(),
+            _ => self.signal.take(),
+        }
+    }
+
+    pub fn get_self(&self) -> Option<ContextualObject> {
         match &self.slf {
             Some(obj) => Some(obj.clone()),
             None => match &self.parent {
@@ -111,14 +414,19 @@ impl<'a> Scope<'a> {
     }
 
     pub fn new_from_object(
-        o: ContextualObject<'a>,
-        parent: MutScope<'a>,
-    ) -> Result<MutScope<'a>, Error> {
+        o: ContextualObject,
+        parent: MutScope,
+    ) -> Result<MutScope, Error> {
         let mut scope = Scope {
             name: "object".to_string(),
             store: BTreeMap::new(),
+            types: BTreeMap::new(),
             parent: Some(parent.clone()),
             slf: Some(o.clone()),
+            methods: BTreeMap::new(),
+            trait_methods: BTreeMap::new(),
+            signal: None,
+            exports: Vec::new(),
         };
 
         match &o.0 {
@@ -132,6 +440,45 @@ impl<'a> Scope<'a> {
                     scope.force_set(&k.0.to_string(), v.clone());
                 }
             }
+            Object::Struct(_, fields) => {
+                for (k, v) in fields.iter() {
+                    scope.force_set(k, v.clone());
+                }
+            }
+            Object::EnumType(name, variants) => {
+                for (variant, shape) in variants.iter() {
+                    scope.force_set(
+                        variant,
+                        match shape {
+                            VariantShape::Unit => Object::Enum(name.clone(), variant.clone(), None),
+                            _ => Object::VariantConstructor(
+                                name.clone(),
+                                variant.clone(),
+                                shape.clone(),
+                            ),
+                        }
+                        .anonymous(),
+                    );
+                }
+            }
+            Object::Enum(_, _, Some(payload)) => match &payload.0 {
+                Object::Array(a) => {
+                    for (i, obj) in a.iter().enumerate() {
+                        scope.force_set(&i.to_string(), obj.clone());
+                    }
+                }
+                Object::Struct(_, fields) => {
+                    for (k, v) in fields.iter() {
+                        scope.force_set(k, v.clone());
+                    }
+                }
+                _ => {}
+            },
+            Object::Module(_, exports) => {
+                for (k, v) in exports.iter() {
+                    scope.force_set(k, v.clone());
+                }
+            }
             Object::String(s) => {
                 for (i, c) in s.chars().enumerate() {
                     scope.force_set(&i.to_string(), Object::String(c.to_string()).anonymous());
@@ -144,11 +491,20 @@ impl<'a> Scope<'a> {
             scope.force_set(intrinsic, get_intrinsic(intrinsic).unwrap());
         }
 
+        let typed = match &o.0 {
+            Object::StructType(name, ..) | Object::EnumType(name, ..) => name.clone(),
+            _ => o.0.typed(),
+        };
+
+        for (name, method) in parent.read().unwrap().get_methods(&typed) {
+            scope.force_set(&name, method);
+        }
+
         Ok(Arc::new(RwLock::new(scope.clone())))
     }
 
     // This is just for nider debugging in the repl
-    pub fn to_object(&self) -> ContextualObject<'a> {
+    pub fn to_object(&self) -> ContextualObject {
         Object::Map({
             let mut m = BTreeMap::new();
             m.insert(string("name"), string(&self.name));
@@ -171,7 +527,7 @@ impl<'a> Scope<'a> {
                         .clone()
                         .iter()
                         .map(|(k, v)| (string(k.as_str()), v.clone()))
-                        .collect::<BTreeMap<ContextualObject<'a>, ContextualObject<'a>>>()
+                        .collect::<BTreeMap<ContextualObject, ContextualObject>>()
                 })
                 .anonymous(),
             );
@@ -182,6 +538,28 @@ impl<'a> Scope<'a> {
     }
 }
 
-fn string<'a>(s: &str) -> ContextualObject<'a> {
+// Collects the scopes captured by closures anywhere inside `value`
+fn captured_by(value: &Object, found: &mut Vec<MutScope>) {
+    match value {
+        Object::Function(.., captured) | Object::Lambda(.., captured) => {
+            found.push(captured.0.clone())
+        }
+        Object::Array(items) => items.iter().for_each(|i| captured_by(&i.0, found)),
+        Object::Map(map) => map.iter().for_each(|(k, v)| {
+            captured_by(&k.0, found);
+            captured_by(&v.0, found);
+        }),
+        Object::Struct(_, fields) => fields.iter().for_each(|(_, v)| captured_by(&v.0, found)),
+        Object::Trait(_, _, values) | Object::Module(_, values) => {
+            values.values().for_each(|v| captured_by(&v.0, found))
+        }
+        Object::Enum(_, _, Some(payload)) | Object::Return(payload) => {
+            captured_by(&payload.0, found)
+        }
+        _ => {}
+    }
+}
+
+fn string(s: &str) -> ContextualObject {
     Object::String(s.to_string()).anonymous()
 }
@@ -5,7 +5,7 @@ use std::{fmt::Debug, ops::Mul};
 macro_rules! map {
     ($value:expr, $max:ty, $max_slf:expr, $($ty:ty => $slf:expr),*) => {
         if false { unreachable!() }
-        $(else if ($value <= <$ty>::MAX as $max) { $slf($value as $ty) })*
+        $(else if (<$ty>::MIN as $max..=<$ty>::MAX as $max).contains(&$value) { $slf($value as $ty) })*
         else { $max_slf($value) }
     };
 }
@@ -0,0 +1,124 @@
+//! Runs every script in `spec/` and checks what it prints against the
+//! `.out` file next to it. Scripts in `spec/fail/` have to fail instead, and
+//! their errors are checked against the `.err` file next to them. Colors are
+//! stripped before comparing.
+//!
+//! `pet check` has to fail on every script in `spec/check/`, and what it
+//! reports is checked against the `.err` file next to each one.
+//!
+//! `pet doc` is checked against `spec/comments.doc` the same way.
+//!
+//! Set `PET_BLESS=1` to write the current output as the expected output.
+
+use std::{fs, path::Path, process::Command};
+
+fn strip_colors(output: &str) -> String {
+    let mut stripped = String::with_capacity(output.len());
+    let mut chars = output.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+// Runs `pet` on `script`, expecting it to succeed or fail, and gives back what
+// it printed to stdout or stderr respectively
+fn run(command: &[&str], script: &Path, succeeds: bool) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_pet"))
+        .args(command)
+        .arg(script)
+        .output()
+        .expect("failed to run pet");
+
+    let stdout = strip_colors(&String::from_utf8_lossy(&output.stdout));
+    let stderr = strip_colors(&String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(
+        output.status.success(),
+        succeeds,
+        "{} exited with {}:\n{stdout}{stderr}",
+        script.display(),
+        output.status,
+    );
+
+    match succeeds {
+        true => stdout,
+        false => stderr,
+    }
+}
+
+fn compare(dir: &str, extension: &str, command: &[&str], succeeds: bool) {
+    let bless = std::env::var_os("PET_BLESS").is_some();
+    let mut scripts = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "pet"))
+        .collect::<Vec<_>>();
+    scripts.sort();
+
+    let mut failed = vec![];
+    for script in scripts {
+        let actual = run(command, &script, succeeds);
+        let expected = script.with_extension(extension);
+
+        if bless {
+            fs::write(&expected, actual).unwrap();
+            continue;
+        }
+
+        match fs::read_to_string(&expected) {
+            Ok(expected) if expected == actual => {}
+            Ok(expected) => failed.push(format!(
+                "{}\n--- expected\n{expected}--- actual\n{actual}",
+                script.display()
+            )),
+            Err(_) => failed.push(format!("{} has no {}", script.display(), expected.display())),
+        }
+    }
+
+    assert!(failed.is_empty(), "\n{}", failed.join("\n"));
+}
+
+#[test]
+fn specs() {
+    compare("spec", "out", &[], true);
+}
+
+#[test]
+fn failures() {
+    compare("spec/fail", "err", &[], false);
+}
+
+#[test]
+fn check() {
+    compare("spec/check", "err", &["check"], false);
+}
+
+#[test]
+fn docs() {
+    let output = Command::new(env!("CARGO_BIN_EXE_pet"))
+        .args(["doc", "spec/comments.pet"])
+        .output()
+        .expect("failed to run pet");
+    let actual = strip_colors(&String::from_utf8_lossy(&output.stdout));
+
+    if std::env::var_os("PET_BLESS").is_some() {
+        fs::write("spec/comments.doc", actual).unwrap();
+        return;
+    }
+    assert_eq!(fs::read_to_string("spec/comments.doc").unwrap(), actual);
+}
+
+#[test]
+fn missing_file() {
+    let stderr = run(&[], Path::new("spec/missing.pet"), false);
+    assert!(stderr.contains("Couldn't read spec/missing.pet"), "{stderr}");
+}